@@ -0,0 +1,296 @@
+use crate::codec::decode_offer;
+use crate::db::{Database, Shards};
+use crate::models::{Facets, Offer};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use rocksdb::IteratorMode;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+pub type SharedCache = Arc<RwLock<OfferCache>>;
+pub type SharedFacetCache = Arc<FacetCache>;
+
+// Identifies the contents of the regions a search covers: the number of clears and the
+// sum of the regions' write counters. Counters only grow, so any write changes it.
+pub type RegionStamp = (u64, u64);
+
+// In-memory copy of every offer in RocksDB, grouped by region and sorted by
+// startDate so queries can binary search the time window.
+// Writers update it after RocksDB while holding the WriteLock, see state.rs.
+#[derive(Debug)]
+pub struct OfferCache {
+    regions: HashMap<i32, Vec<Offer>>,
+    offer_regions: HashMap<Uuid, i32>,
+    // Writes per region and clears, see RegionStamp
+    generations: HashMap<i32, u64>,
+    clears: u64,
+    // Generation of the whole cache, bumped by every change and watched by long-polls.
+    // It starts over at 0 with the process.
+    changes: watch::Sender<u64>,
+    // Random per cache, and so per process, telling generations of different runs apart
+    epoch: u64,
+}
+
+impl Default for OfferCache {
+    fn default() -> Self {
+        OfferCache {
+            regions: HashMap::new(),
+            offer_regions: HashMap::new(),
+            generations: HashMap::new(),
+            clears: 0,
+            changes: watch::channel(0).0,
+            epoch: Uuid::new_v4().as_u64_pair().0,
+        }
+    }
+}
+
+impl OfferCache {
+    pub fn load(db: &Database, shards: Shards) -> Result<Self, Box<dyn std::error::Error>> {
+        // Read the raw values of every shard first so decoding can run in parallel
+        let mut entries = Vec::new();
+        for shard in shards.handles(db)? {
+            for item in db.iterator_cf(&shard, IteratorMode::Start) {
+                entries.push(item?);
+            }
+        }
+
+        let decode = |(key, value): &(Box<[u8]>, Box<[u8]>)| match decode_offer(value) {
+            Ok(offer) => Some(offer),
+            Err(e) => {
+                tracing::error!("Failed to decode offer {:?}: {}", key, e);
+                None
+            }
+        };
+        #[cfg(feature = "parallel")]
+        let offers: Vec<Offer> = entries.par_iter().filter_map(decode).collect();
+        #[cfg(not(feature = "parallel"))]
+        let offers: Vec<Offer> = entries.iter().filter_map(decode).collect();
+
+        let mut cache = OfferCache::default();
+        cache.insert(offers);
+        Ok(cache)
+    }
+
+    pub fn len(&self) -> usize {
+        self.offer_regions.len()
+    }
+
+    // Offers of one region sorted by (startDate, ID)
+    pub fn region(&self, region_id: i32) -> &[Offer] {
+        self.regions.get(&region_id).map_or(&[], |offers| offers.as_slice())
+    }
+
+    // Every region that ever had an offer, in no particular order
+    pub fn region_ids(&self) -> impl Iterator<Item = i32> + '_ {
+        self.regions.keys().copied()
+    }
+
+    // Every offer, in no particular order
+    pub fn offers(&self) -> impl Iterator<Item = &Offer> {
+        self.regions.values().flatten()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    // Sees every generation after the current one
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    pub fn stamp<'a>(&self, region_ids: impl IntoIterator<Item = &'a i32>) -> RegionStamp {
+        let writes = region_ids
+            .into_iter()
+            .map(|id| self.generations.get(id).copied().unwrap_or(0))
+            .sum();
+        (self.clears, writes)
+    }
+
+    // Inserts or replaces offers by ID
+    pub fn insert(&mut self, offers: impl IntoIterator<Item = Offer>) {
+        let offers: Vec<Offer> = offers.into_iter().collect();
+        if offers.is_empty() {
+            return;
+        }
+
+        // Stored versions go first, they may live in another region than the new one
+        let mut touched = self.remove_offers(offers.iter().map(|offer| &offer.ID));
+        for offer in offers {
+            self.offer_regions.insert(offer.ID, offer.mostSpecificRegionID);
+            touched.insert(offer.mostSpecificRegionID);
+            self.regions.entry(offer.mostSpecificRegionID).or_default().push(offer);
+        }
+
+        for &region_id in &touched {
+            if let Some(offers) = self.regions.get_mut(&region_id) {
+                offers.sort_by(|a, b| a.startDate.cmp(&b.startDate).then_with(|| a.ID.cmp(&b.ID)));
+            }
+        }
+        self.touch(touched);
+    }
+
+    pub fn remove(&mut self, id: &Uuid) {
+        self.remove_many([id]);
+    }
+
+    // Removes offers by ID with one pass over each region losing some, unknown IDs are skipped
+    pub fn remove_many<'a>(&mut self, ids: impl IntoIterator<Item = &'a Uuid>) {
+        let touched = self.remove_offers(ids);
+        self.touch(touched);
+    }
+
+    pub fn clear(&mut self) {
+        self.regions.clear();
+        self.offer_regions.clear();
+        self.clears += 1;
+        self.bump();
+    }
+
+    // Retaining once per region keeps removing k offers from a region of n at O(n + k)
+    // instead of O(k·n). Returns the regions that lost offers and leaves the generations
+    // alone, so a batch bumps them once.
+    fn remove_offers<'a>(&mut self, ids: impl IntoIterator<Item = &'a Uuid>) -> HashSet<i32> {
+        let mut removed: HashMap<i32, HashSet<Uuid>> = HashMap::new();
+        for id in ids {
+            if let Some(region_id) = self.offer_regions.remove(id) {
+                removed.entry(region_id).or_default().insert(*id);
+            }
+        }
+        for (region_id, ids) in &removed {
+            if let Some(offers) = self.regions.get_mut(region_id) {
+                offers.retain(|offer| !ids.contains(&offer.ID));
+            }
+        }
+        removed.into_keys().collect()
+    }
+
+    // Bumps the write counters of the changed regions and the generation once
+    fn touch(&mut self, region_ids: HashSet<i32>) {
+        if region_ids.is_empty() {
+            return;
+        }
+        for region_id in region_ids {
+            *self.generations.entry(region_id).or_default() += 1;
+        }
+        self.bump();
+    }
+
+    fn bump(&mut self) {
+        self.changes.send_modify(|generation| *generation += 1);
+    }
+}
+
+// Bound on remembered searches, the cache simply starts over when it is full
+const FACET_CACHE_CAPACITY: usize = 1024;
+
+// Sorted name and value pairs of the facet relevant query parameters of a search
+pub type FacetKey = Vec<(String, String)>;
+
+// Facets of recent searches keyed by their facet relevant query parameters.
+// An entry is only reused while the stamp of the searched regions is unchanged,
+// so a write to a region invalidates every search covering it.
+#[derive(Default, Debug)]
+pub struct FacetCache {
+    entries: Mutex<HashMap<FacetKey, (RegionStamp, Facets)>>,
+}
+
+impl FacetCache {
+    pub fn get(&self, key: &[(String, String)], stamp: RegionStamp) -> Option<Facets> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((entry_stamp, facets)) if *entry_stamp == stamp => Some(facets.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, key: FacetKey, stamp: RegionStamp, facets: Facets) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= FACET_CACHE_CAPACITY && !entries.contains_key(&key) {
+            entries.clear();
+        }
+        entries.insert(key, (stamp, facets));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{encode_offer, DataCompression};
+    use crate::db::{init_db, offer_key, DbConfig};
+    use crate::testutil::{offer, TempDir};
+
+    #[test]
+    fn load_skips_values_that_fail_to_decode() {
+        let dir = TempDir::new();
+        let config = DbConfig::default();
+        let shards = Shards::new(config.shards);
+        let db = init_db(dir.path(), &config).unwrap();
+        let valid = offer();
+        let shard = shards.of_region_handle(&db, valid.mostSpecificRegionID).unwrap();
+        db.put_cf(&shard, offer_key(&valid.ID), encode_offer(&valid, DataCompression::None).unwrap()).unwrap();
+        db.put_cf(&shard, offer_key(&Uuid::new_v4()), [0xde, 0xad]).unwrap();
+
+        let cache = OfferCache::load(&db, shards).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.region(valid.mostSpecificRegionID)[0].ID, valid.ID);
+    }
+
+    // Looks an offer up by its position in the region's sort order
+    fn find<'a>(cache: &'a OfferCache, region_id: i32, start_date: i64, id: &Uuid) -> Option<&'a Offer> {
+        let offers = cache.region(region_id);
+        offers
+            .binary_search_by(|offer| offer.startDate.cmp(&start_date).then_with(|| offer.ID.cmp(id)))
+            .ok()
+            .map(|index| &offers[index])
+    }
+
+    // Every offer is indexed under its region, and every region stays sorted
+    fn assert_consistent(cache: &OfferCache) {
+        assert_eq!(cache.len(), cache.offers().count());
+        for offer in cache.offers() {
+            let found = find(cache, offer.mostSpecificRegionID, offer.startDate, &offer.ID).unwrap();
+            assert_eq!(found.ID, offer.ID);
+        }
+        for region_id in cache.region_ids() {
+            let keys: Vec<(i64, Uuid)> = cache.region(region_id).iter().map(|offer| (offer.startDate, offer.ID)).collect();
+            assert!(keys.is_sorted());
+        }
+    }
+
+    #[test]
+    fn insert_remove_and_clear_keep_the_indexes_in_sync() {
+        let mut cache = OfferCache::default();
+        let offers: Vec<Offer> = (0..6)
+            .map(|i| Offer {
+                mostSpecificRegionID: i % 2,
+                startDate: (6 - i as i64) * 1000,
+                ..offer()
+            })
+            .collect();
+        cache.insert(offers.clone());
+        assert_consistent(&cache);
+        assert_eq!(cache.len(), 6);
+
+        // Replacing an offer may move it to another region
+        let moved = Offer {
+            mostSpecificRegionID: 7,
+            ..offers[0].clone()
+        };
+        cache.insert([moved]);
+        assert_consistent(&cache);
+        assert_eq!(cache.len(), 6);
+        assert!(find(&cache, 0, offers[0].startDate, &offers[0].ID).is_none());
+        assert_eq!(cache.region(7).len(), 1);
+
+        cache.remove_many([&offers[1].ID, &offers[2].ID, &Uuid::new_v4()]);
+        assert_consistent(&cache);
+        assert_eq!(cache.len(), 4);
+
+        cache.clear();
+        assert_consistent(&cache);
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,227 @@
+// Storage encoding of offers in RocksDB, the HTTP API stays JSON.
+//
+// Values are bincode since JSON decoding dominated full scans. Databases written
+// by earlier versions stored JSON values and can't be decoded anymore: clean them
+// up with DELETE /api/offers and upload the offers again.
+//
+// Every value starts with a format byte naming its layout, so fields can be added
+// without breaking stored offers. Values from before the format byte are bincode
+// offers, the oldest of them without a createdAt, which stays unset when decoding them.
+use crate::models::{CarType, Offer};
+use serde::Deserialize;
+use std::str::FromStr;
+use uuid::Uuid;
+
+// Optional compression of the data of stored offers, see DATA_COMPRESSION.
+// Decoding handles both layouts, so changing it leaves existing values readable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DataCompression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl FromStr for DataCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(DataCompression::None),
+            "zstd" => Ok(DataCompression::Zstd),
+            _ => Err(format!("expected none or zstd, got {:?}", s)),
+        }
+    }
+}
+
+// Format bytes, each followed by a bincode Offer. Unversioned values start with the length
+// of the offer ID, which bincode writes as a little endian u64 16, so with neither of these.
+// The current Offer with plain data
+const FORMAT_PLAIN: u8 = 0x01;
+// The current Offer with zstd compressed data
+const FORMAT_ZSTD: u8 = 0xff;
+const ZSTD_LEVEL: i32 = 3;
+
+pub fn encode_offer(offer: &Offer, compression: DataCompression) -> Result<Vec<u8>, bincode::Error> {
+    match compression {
+        DataCompression::None => {
+            let mut bytes = vec![FORMAT_PLAIN];
+            bincode::serialize_into(&mut bytes, offer)?;
+            Ok(bytes)
+        }
+        DataCompression::Zstd => {
+            let mut stored = offer.clone();
+            stored.data = zstd::bulk::compress(&offer.data, ZSTD_LEVEL)?;
+            let mut bytes = vec![FORMAT_ZSTD];
+            bincode::serialize_into(&mut bytes, &stored)?;
+            Ok(bytes)
+        }
+    }
+}
+
+pub fn decode_offer(bytes: &[u8]) -> Result<Offer, bincode::Error> {
+    match bytes.split_first() {
+        Some((&FORMAT_PLAIN, stored)) => bincode::deserialize(stored),
+        Some((&FORMAT_ZSTD, stored)) => {
+            let mut offer: Offer = bincode::deserialize(stored)?;
+            offer.data = zstd::decode_all(offer.data.as_slice())?;
+            Ok(offer)
+        }
+        // Unversioned, with or without a createdAt. Reading the current Offer from the
+        // layout without one runs out of bytes.
+        _ => bincode::deserialize(bytes)
+            .or_else(|_| bincode::deserialize::<UnversionedOffer>(bytes).map(Offer::from)),
+    }
+}
+
+// Offer before it had a createdAt, bincode only relies on the field order
+#[derive(Deserialize)]
+struct UnversionedOffer {
+    id: Uuid,
+    data: Vec<u8>,
+    most_specific_region_id: i32,
+    start_date: i64,
+    end_date: i64,
+    number_seats: u8,
+    price: u16,
+    car_type: CarType,
+    has_vollkasko: bool,
+    free_kilometers: u16,
+}
+
+impl From<UnversionedOffer> for Offer {
+    fn from(offer: UnversionedOffer) -> Self {
+        Offer {
+            ID: offer.id,
+            data: offer.data,
+            mostSpecificRegionID: offer.most_specific_region_id,
+            startDate: offer.start_date,
+            endDate: offer.end_date,
+            numberSeats: offer.number_seats,
+            price: offer.price,
+            carType: offer.car_type,
+            hasVollkasko: offer.has_vollkasko,
+            freeKilometers: offer.free_kilometers,
+            createdAt: None,
+        }
+    }
+}
+
+// Snapshots used by export/import are every offer as a little endian u32 length
+// followed by that many bytes of encode_offer output, always uncompressed.
+pub fn encode_snapshot<'a>(offers: impl IntoIterator<Item = &'a Offer>) -> Result<Vec<u8>, bincode::Error> {
+    let mut snapshot = Vec::new();
+    for offer in offers {
+        let bytes = encode_offer(offer, DataCompression::None)?;
+        snapshot.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        snapshot.extend_from_slice(&bytes);
+    }
+    Ok(snapshot)
+}
+
+pub fn decode_snapshot(mut snapshot: &[u8]) -> Result<Vec<Offer>, String> {
+    let mut offers = Vec::new();
+    while !snapshot.is_empty() {
+        if snapshot.len() < 4 {
+            return Err(format!("Truncated length prefix after {} offers", offers.len()));
+        }
+        let (prefix, rest) = snapshot.split_at(4);
+        let len = u32::from_le_bytes(prefix.try_into().unwrap()) as usize;
+        if rest.len() < len {
+            return Err(format!("Truncated offer after {} offers", offers.len()));
+        }
+        let (bytes, rest) = rest.split_at(len);
+        let offer = decode_offer(bytes).map_err(|e| format!("Invalid offer after {} offers: {}", offers.len(), e))?;
+        offers.push(offer);
+        snapshot = rest;
+    }
+    Ok(offers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::offer;
+
+    #[test]
+    fn decodes_values_from_before_created_at() {
+        let offer = Offer {
+            createdAt: Some(5),
+            ..offer()
+        };
+        // The unversioned layout is the fields in order, without createdAt
+        let legacy = bincode::serialize(&(
+            offer.ID,
+            &offer.data,
+            offer.mostSpecificRegionID,
+            offer.startDate,
+            offer.endDate,
+            offer.numberSeats,
+            offer.price,
+            offer.carType,
+            offer.hasVollkasko,
+            offer.freeKilometers,
+        ))
+        .unwrap();
+
+        let decoded = decode_offer(&legacy).unwrap();
+        assert_eq!(decoded.ID, offer.ID);
+        assert_eq!(decoded.data, offer.data);
+        assert_eq!(decoded.endDate, offer.endDate);
+        assert_eq!(decoded.freeKilometers, offer.freeKilometers);
+        assert_eq!(decoded.createdAt, None);
+    }
+
+    #[test]
+    fn decodes_unversioned_values_with_created_at() {
+        let offer = Offer {
+            createdAt: Some(5),
+            ..offer()
+        };
+        let decoded = decode_offer(&bincode::serialize(&offer).unwrap()).unwrap();
+        assert_eq!(decoded.ID, offer.ID);
+        assert_eq!(decoded.createdAt, Some(5));
+    }
+
+    #[test]
+    fn values_start_with_their_format() {
+        let offer = offer();
+        assert_eq!(encode_offer(&offer, DataCompression::None).unwrap()[0], FORMAT_PLAIN);
+        assert_eq!(encode_offer(&offer, DataCompression::Zstd).unwrap()[0], FORMAT_ZSTD);
+    }
+
+    #[test]
+    fn encoded_offers_decode_to_the_same_offer() {
+        let offers = [
+            offer(),
+            Offer {
+                carType: CarType::Luxury,
+                hasVollkasko: true,
+                createdAt: Some(1_700_000_000_000),
+                startDate: -5,
+                ..offer()
+            },
+        ];
+        for offer in &offers {
+            let decoded = decode_offer(&encode_offer(offer, DataCompression::None).unwrap()).unwrap();
+            assert_eq!(serde_json::to_value(decoded).unwrap(), serde_json::to_value(offer).unwrap());
+        }
+    }
+
+    #[test]
+    fn zstd_values_decode_to_the_same_offer() {
+        let offers = [
+            offer(),
+            Offer {
+                data: (0..=255u8).cycle().take(4096).collect(),
+                createdAt: Some(1_700_000_000_000),
+                ..offer()
+            },
+        ];
+        for offer in &offers {
+            let compressed = encode_offer(offer, DataCompression::Zstd).unwrap();
+            assert!(compressed.len() < encode_offer(offer, DataCompression::None).unwrap().len());
+            let decoded = decode_offer(&compressed).unwrap();
+            assert_eq!(serde_json::to_value(decoded).unwrap(), serde_json::to_value(offer).unwrap());
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use crate::codec::DataCompression;
+use crate::db::DbConfig;
+use axum::http::HeaderValue;
+use rocksdb::DBCompressionType;
+use std::env;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:80";
+const DEFAULT_DB_PATH: &str = "offers.db";
+const DEFAULT_REGIONS_PATH: &str = "regions.json";
+const DEFAULT_BODY_LIMIT: usize = 50 * 1024 * 1024;
+// A snapshot of a million offers is about 330 MB
+const DEFAULT_IMPORT_BODY_LIMIT: usize = 1024 * 1024 * 1024;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_MAX_BATCH_IDS: usize = 1000;
+
+// Runtime settings read from the environment at startup
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind_addr: SocketAddr,
+    pub db_path: String,
+    pub db: DbConfig,
+    pub regions_path: String,
+    // Largest accepted POST /api/offers body in bytes
+    pub body_limit: usize,
+    // Largest accepted POST /api/offers/import snapshot in bytes
+    pub import_body_limit: usize,
+    // Tokio worker threads, the CPU count by default
+    pub worker_threads: usize,
+    // Seconds between passes deleting offers whose endDate has passed, 0 disables them
+    pub expiry_interval_secs: u64,
+    // Requests still running after this many milliseconds get a 408, except the long running ones
+    pub request_timeout_ms: u64,
+    // Origins allowed by CORS, None allows any origin
+    pub cors_allow_origin: Option<Vec<HeaderValue>>,
+    // Secret expected in the X-Admin-Token header of admin endpoints, None disables them
+    pub admin_token: Option<String>,
+    // Most IDs a POST /api/offers/batch request may ask for
+    pub max_batch_ids: usize,
+    // Requests handled at once, 0 for no limit. /health is never limited.
+    pub max_concurrency: usize,
+    // Answer requests over the limit with a 503 instead of queueing them
+    pub load_shed: bool,
+    // Searches without a regionID, or with regionID=all, scan every region
+    pub allow_global_query: bool,
+    // Compression of the data of offers written from now on, none or zstd
+    pub data_compression: DataCompression,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(|key| env::var(key).ok())
+    }
+
+    // Reads every setting through var, which returns the value of a variable if it is set
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let bind_addr = parse_bind_addr(&env_or(&var, "BIND_ADDR", DEFAULT_BIND_ADDR))?;
+        let db_path = env_or(&var, "DB_PATH", DEFAULT_DB_PATH);
+
+        let defaults = DbConfig::default();
+        let db = DbConfig {
+            parallelism: env_parse(&var, "DB_PARALLELISM", defaults.parallelism)?,
+            max_background_jobs: env_parse(&var, "DB_MAX_BACKGROUND_JOBS", defaults.max_background_jobs)?,
+            write_buffer_size: env_parse(&var, "DB_WRITE_BUFFER_SIZE", defaults.write_buffer_size)?,
+            compression: match var("DB_COMPRESSION") {
+                Some(value) => parse_compression(&value)?,
+                None => defaults.compression,
+            },
+            shards: env_parse(&var, "DB_SHARDS", defaults.shards)?,
+            write_retries: env_parse(&var, "DB_WRITE_RETRIES", defaults.write_retries)?,
+            write_retry_delay_ms: env_parse(&var, "DB_WRITE_RETRY_DELAY_MS", defaults.write_retry_delay_ms)?,
+        };
+
+        let regions_path = env_or(&var, "REGIONS_PATH", DEFAULT_REGIONS_PATH);
+        let body_limit = env_parse(&var, "BODY_LIMIT_BYTES", DEFAULT_BODY_LIMIT)?;
+        let import_body_limit = env_parse(&var, "IMPORT_BODY_LIMIT_BYTES", DEFAULT_IMPORT_BODY_LIMIT)?;
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let worker_threads = env_parse(&var, "WORKER_THREADS", cpus)?;
+        // Tokio panics on a runtime without workers
+        if worker_threads == 0 {
+            return Err("Invalid WORKER_THREADS \"0\": must be at least 1".to_string());
+        }
+        let expiry_interval_secs = env_parse(&var, "EXPIRY_INTERVAL_SECS", 0)?;
+        let request_timeout_ms = env_parse(&var, "REQUEST_TIMEOUT_MS", DEFAULT_REQUEST_TIMEOUT_MS)?;
+        let cors_allow_origin = parse_cors_allow_origin(&env_or(&var, "CORS_ALLOW_ORIGIN", "*"))?;
+        let admin_token = var("ADMIN_TOKEN").filter(|token| !token.is_empty());
+        let max_batch_ids = env_parse(&var, "MAX_BATCH_IDS", DEFAULT_MAX_BATCH_IDS)?;
+        let max_concurrency = env_parse(&var, "MAX_CONCURRENCY", 0)?;
+        let load_shed = var("LOAD_SHED").is_some_and(|value| value == "1");
+        let allow_global_query = var("ALLOW_GLOBAL_QUERY").is_some_and(|value| value == "1");
+        let data_compression = env_parse(&var, "DATA_COMPRESSION", DataCompression::None)?;
+
+        Ok(Config {
+            bind_addr,
+            db_path,
+            db,
+            regions_path,
+            body_limit,
+            import_body_limit,
+            worker_threads,
+            expiry_interval_secs,
+            request_timeout_ms,
+            cors_allow_origin,
+            admin_token,
+            max_batch_ids,
+            max_concurrency,
+            load_shed,
+            allow_global_query,
+            data_compression,
+        })
+    }
+}
+
+// "*" allows any origin, otherwise a comma separated list of origins
+pub fn parse_cors_allow_origin(value: &str) -> Result<Option<Vec<HeaderValue>>, String> {
+    if value.trim() == "*" {
+        return Ok(None);
+    }
+    value
+        .split(',')
+        .map(|origin| {
+            HeaderValue::from_str(origin.trim()).map_err(|e| format!("Invalid CORS_ALLOW_ORIGIN {:?}: {}", origin, e))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+fn env_parse<T>(var: &impl Fn(&str) -> Option<String>, key: &str, default: T) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match var(key) {
+        Some(value) => value
+            .parse()
+            .map_err(|e| format!("Invalid {} {:?}: {}", key, value, e)),
+        None => Ok(default),
+    }
+}
+
+fn env_or(var: &impl Fn(&str) -> Option<String>, key: &str, default: &str) -> String {
+    var(key).unwrap_or_else(|| default.to_string())
+}
+
+pub fn parse_bind_addr(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse()
+        .map_err(|e| format!("Invalid BIND_ADDR {:?}: {}", value, e))
+}
+
+pub fn parse_compression(value: &str) -> Result<DBCompressionType, String> {
+    match value {
+        "none" => Ok(DBCompressionType::None),
+        "snappy" => Ok(DBCompressionType::Snappy),
+        "lz4" => Ok(DBCompressionType::Lz4),
+        "zstd" => Ok(DBCompressionType::Zstd),
+        other => Err(format!("Invalid DB_COMPRESSION {:?}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn config(vars: &[(&str, &str)]) -> Result<Config, String> {
+        let vars: HashMap<String, String> =
+            vars.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        Config::from_vars(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn defaults_without_variables() {
+        let config = config(&[]).unwrap();
+        assert_eq!(config.bind_addr, DEFAULT_BIND_ADDR.parse().unwrap());
+        assert_eq!(config.db_path, DEFAULT_DB_PATH);
+        assert_eq!(config.regions_path, DEFAULT_REGIONS_PATH);
+        assert_eq!(config.body_limit, DEFAULT_BODY_LIMIT);
+        assert_eq!(config.import_body_limit, DEFAULT_IMPORT_BODY_LIMIT);
+        assert_eq!(config.request_timeout_ms, DEFAULT_REQUEST_TIMEOUT_MS);
+        assert_eq!(config.max_batch_ids, DEFAULT_MAX_BATCH_IDS);
+        assert_eq!(config.expiry_interval_secs, 0);
+        assert_eq!(config.max_concurrency, 0);
+        assert!(config.cors_allow_origin.is_none());
+        assert!(config.admin_token.is_none());
+        assert!(!config.load_shed);
+        assert!(!config.allow_global_query);
+    }
+
+    #[test]
+    fn variables_override_the_defaults() {
+        let config = config(&[
+            ("BIND_ADDR", "127.0.0.1:8080"),
+            ("DB_PATH", "/data/offers.db"),
+            ("REGIONS_PATH", "/data/regions.json"),
+            ("BODY_LIMIT_BYTES", "1024"),
+            ("REQUEST_TIMEOUT_MS", "500"),
+            ("ADMIN_TOKEN", "secret"),
+            ("LOAD_SHED", "1"),
+        ])
+        .unwrap();
+        assert_eq!(config.bind_addr, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.db_path, "/data/offers.db");
+        assert_eq!(config.regions_path, "/data/regions.json");
+        assert_eq!(config.body_limit, 1024);
+        assert_eq!(config.request_timeout_ms, 500);
+        assert_eq!(config.admin_token.as_deref(), Some("secret"));
+        assert!(config.load_shed);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(config(&[("BIND_ADDR", "localhost")]).unwrap_err().contains("BIND_ADDR"));
+        assert!(config(&[("BODY_LIMIT_BYTES", "lots")]).unwrap_err().contains("BODY_LIMIT_BYTES"));
+    }
+
+    #[test]
+    fn empty_admin_token_disables_the_admin_endpoints() {
+        assert!(config(&[("ADMIN_TOKEN", "")]).unwrap().admin_token.is_none());
+    }
+
+    #[test]
+    fn db_variables_configure_rocksdb() {
+        let defaults = DbConfig::default();
+        let db = config(&[]).unwrap().db;
+        assert_eq!(db.parallelism, defaults.parallelism);
+        assert_eq!(db.compression, DBCompressionType::Lz4);
+        assert_eq!(db.shards, 1);
+
+        let db = config(&[
+            ("DB_PARALLELISM", "2"),
+            ("DB_MAX_BACKGROUND_JOBS", "8"),
+            ("DB_WRITE_BUFFER_SIZE", "1048576"),
+            ("DB_COMPRESSION", "zstd"),
+            ("DB_SHARDS", "4"),
+            ("DB_WRITE_RETRIES", "5"),
+            ("DB_WRITE_RETRY_DELAY_MS", "20"),
+        ])
+        .unwrap()
+        .db;
+        assert_eq!(db.parallelism, 2);
+        assert_eq!(db.max_background_jobs, 8);
+        assert_eq!(db.write_buffer_size, 1048576);
+        assert_eq!(db.compression, DBCompressionType::Zstd);
+        assert_eq!(db.shards, 4);
+        assert_eq!(db.write_retry().retries, 5);
+        assert_eq!(db.write_retry().base_delay, Duration::from_millis(20));
+
+        assert!(config(&[("DB_COMPRESSION", "gzip")]).unwrap_err().contains("DB_COMPRESSION"));
+        assert!(config(&[("DB_SHARDS", "-1")]).unwrap_err().contains("DB_SHARDS"));
+    }
+
+    #[test]
+    fn worker_threads_default_to_the_cpus_and_need_at_least_one() {
+        let cpus = std::thread::available_parallelism().unwrap().get();
+        assert_eq!(config(&[]).unwrap().worker_threads, cpus);
+        assert_eq!(config(&[("WORKER_THREADS", "3")]).unwrap().worker_threads, 3);
+        for invalid in ["0", "-2", "many"] {
+            assert!(config(&[("WORKER_THREADS", invalid)]).unwrap_err().contains("WORKER_THREADS"));
+        }
+    }
+}
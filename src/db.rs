@@ -1,11 +1,436 @@
-use rocksdb::{DBWithThreadMode, MultiThreaded, Options};
+use crate::models::Offer;
+use rocksdb::{
+    BoundColumnFamily, DBCompressionType, DBWithThreadMode, IteratorMode, MultiThreaded, Options, WriteBatch,
+    WriteOptions, DB, DEFAULT_COLUMN_FAMILY_NAME,
+};
+use rocksdb::ErrorKind;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
 pub type Database = Arc<DBWithThreadMode<MultiThreaded>>;
 
-pub fn init_db() -> Result<Database, Box<dyn std::error::Error>> {
+// Column family names live here so nothing else spells them out.
+// Offers keyed by offer ID: shard 0 is CF_OFFERS, RocksDB's default column family,
+// shard n > 0 is CF_OFFERS_SHARD_PREFIX followed by n, see Shards.
+pub const CF_OFFERS: &str = DEFAULT_COLUMN_FAMILY_NAME;
+pub const CF_OFFERS_SHARD_PREFIX: &str = "offers_";
+
+// Secondary indexes, all values are empty and the offer ID is the key suffix
+// idx_region: region_id || offer_id
+pub const CF_REGION_INDEX: &str = "idx_region";
+// Every index column family, all opened by init_db
+pub const CF_INDEXES: [&str; 1] = [CF_REGION_INDEX];
+// region_id || startDate || offer_id, unread since searches go through the OfferCache.
+// Databases written before it was dropped still have it, init_db drops it from them.
+const CF_DROPPED_REGION_START_INDEX: &str = "idx_region_start";
+
+// Offers are spread over shard column families by region_id % count, keyed by offer ID,
+// so concurrent writes don't all contend on one memtable. Shard 0 is the default column
+// family, which makes a single shard the unsharded layout. Changing the count of an
+// existing database misroutes its offers: clean it up first and upload them again.
+#[derive(Clone, Copy, Debug)]
+pub struct Shards(usize);
+
+impl Shards {
+    pub fn new(count: usize) -> Self {
+        Shards(count.max(1))
+    }
+
+    pub fn count(self) -> usize {
+        self.0
+    }
+
+    pub fn of_region(self, region_id: i32) -> usize {
+        (region_id as i64).rem_euclid(self.0 as i64) as usize
+    }
+
+    pub fn name(shard: usize) -> String {
+        match shard {
+            0 => CF_OFFERS.to_string(),
+            _ => format!("{}{}", CF_OFFERS_SHARD_PREFIX, shard),
+        }
+    }
+
+    pub fn handle(self, db: &Database, shard: usize) -> Result<Arc<BoundColumnFamily<'_>>, Box<dyn std::error::Error>> {
+        Ok(db.cf_handle(&Self::name(shard)).ok_or("Missing shard column family")?)
+    }
+
+    // Handle of the shard holding the offers of a region
+    pub fn of_region_handle(
+        self,
+        db: &Database,
+        region_id: i32,
+    ) -> Result<Arc<BoundColumnFamily<'_>>, Box<dyn std::error::Error>> {
+        self.handle(db, self.of_region(region_id))
+    }
+
+    // Handles of every shard, indexed by shard number
+    pub fn handles(self, db: &Database) -> Result<Vec<Arc<BoundColumnFamily<'_>>>, Box<dyn std::error::Error>> {
+        (0..self.0).map(|shard| self.handle(db, shard)).collect()
+    }
+}
+
+// RocksDB tuning for our bulk-load-then-scan workload
+#[derive(Clone, Debug)]
+pub struct DbConfig {
+    pub parallelism: i32,
+    pub max_background_jobs: i32,
+    pub write_buffer_size: usize,
+    pub compression: DBCompressionType,
+    pub shards: usize,
+    pub write_retries: u32,
+    pub write_retry_delay_ms: u64,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get()) as i32;
+        DbConfig {
+            parallelism: cpus,
+            max_background_jobs: 4,
+            write_buffer_size: 64 * 1024 * 1024,
+            compression: DBCompressionType::Lz4,
+            shards: 1,
+            write_retries: 3,
+            write_retry_delay_ms: 10,
+        }
+    }
+}
+
+impl DbConfig {
+    pub fn write_retry(&self) -> WriteRetry {
+        WriteRetry {
+            retries: self.write_retries,
+            base_delay: Duration::from_millis(self.write_retry_delay_ms),
+        }
+    }
+}
+
+// How often a write failing with a transient error is tried again, the delay doubles every time
+#[derive(Clone, Copy, Debug)]
+pub struct WriteRetry {
+    pub retries: u32,
+    pub base_delay: Duration,
+}
+
+// Busy or stalled writes, e.g. during heavy compaction, can succeed later.
+// Anything else, like corruption or a full disk, fails right away.
+fn is_transient(error: &rocksdb::Error) -> bool {
+    matches!(error.kind(), ErrorKind::Busy | ErrorKind::TryAgain | ErrorKind::TimedOut)
+}
+
+// Whether a write goes through the write-ahead log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wal {
+    Enabled,
+    // Skips the log, roughly halving the bytes a bulk load writes. The write only lives in
+    // the memtable until it is flushed, so callers flush_all right after it. A crash before
+    // that flush still loses the write, and so does one during it.
+    Disabled,
+}
+
+// Writes a batch, retrying transient errors with exponential backoff.
+// The backoff blocks the thread, handlers write through state::run_write for that reason.
+pub fn write_with_retry(db: &Database, batch: WriteBatch, retry: WriteRetry) -> Result<(), rocksdb::Error> {
+    write_with_retry_wal(db, batch, retry, Wal::Enabled)
+}
+
+pub fn write_with_retry_wal(
+    db: &Database,
+    batch: WriteBatch,
+    retry: WriteRetry,
+    wal: Wal,
+) -> Result<(), rocksdb::Error> {
+    let mut opts = WriteOptions::default();
+    opts.disable_wal(wal == Wal::Disabled);
+
+    // Every attempt consumes the batch, keep its encoded form to rebuild it
+    let data = (retry.retries > 0).then(|| batch.data().to_vec());
+    let mut batch = Some(batch);
+    retry_with(retry, is_transient, || {
+        let batch = batch
+            .take()
+            .unwrap_or_else(|| WriteBatch::from_data(data.as_deref().unwrap_or_default()));
+        db.write_opt(batch, &opts)
+    })
+}
+
+// Runs attempt until it succeeds, fails with an error that isn't transient or used up the retries
+fn retry_with<E: std::fmt::Display>(
+    retry: WriteRetry,
+    transient: impl Fn(&E) -> bool,
+    mut attempt: impl FnMut() -> Result<(), E>,
+) -> Result<(), E> {
+    let mut delay = retry.base_delay;
+    let mut retried = 0;
+    loop {
+        match attempt() {
+            Err(e) if retried < retry.retries && transient(&e) => {
+                retried += 1;
+                tracing::warn!("Write failed, retry {} of {} in {:?}: {}", retried, retry.retries, delay, e);
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Flushes the memtables of every column family, DB::flush only covers the default one
+pub fn flush_all(db: &Database) -> Result<(), rocksdb::Error> {
+    for name in DB::list_cf(&Options::default(), db.path())? {
+        if let Some(cf) = db.cf_handle(&name) {
+            db.flush_cf(&cf)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn init_db(path: &str, config: &DbConfig) -> Result<Database, Box<dyn std::error::Error>> {
     let mut opts = Options::default();
     opts.create_if_missing(true);
-    let db = DBWithThreadMode::<MultiThreaded>::open(&opts, "offers.db")?;
+    opts.create_missing_column_families(true);
+    opts.increase_parallelism(config.parallelism);
+    opts.set_max_background_jobs(config.max_background_jobs);
+    opts.set_write_buffer_size(config.write_buffer_size);
+    opts.set_compression_type(config.compression);
+
+    // RocksDB refuses to open without every existing column family, e.g. the extra
+    // shards after lowering the shard count, so open those as well
+    let mut cf_names: Vec<String> = DB::list_cf(&opts, path).unwrap_or_default();
+    let wanted = CF_INDEXES
+        .iter()
+        .map(|name| name.to_string())
+        .chain((1..config.shards.max(1)).map(Shards::name));
+    for name in wanted {
+        if !cf_names.contains(&name) {
+            cf_names.push(name);
+        }
+    }
+    let dropped_start_index = cf_names.iter().any(|name| name == CF_DROPPED_REGION_START_INDEX);
+    let db = DBWithThreadMode::<MultiThreaded>::open_cf(&opts, path, cf_names)?;
+    if dropped_start_index {
+        db.drop_cf(CF_DROPPED_REGION_START_INDEX)?;
+    }
     Ok(Arc::new(db))
-}
\ No newline at end of file
+}
+
+// Deletes every key in every column family and compacts to reclaim disk space
+pub fn clear_all(db: &Database) -> Result<(), Box<dyn std::error::Error>> {
+    for name in DB::list_cf(&Options::default(), db.path())? {
+        let cf = db.cf_handle(&name).ok_or("Missing column family")?;
+
+        // Range deletes exclude the end key, so end just past the last key
+        let last_key = match db.iterator_cf(&cf, IteratorMode::End).next() {
+            Some(item) => item?.0,
+            None => continue,
+        };
+        let mut end = last_key.into_vec();
+        end.push(0);
+
+        db.delete_range_cf(&cf, &b""[..], &end[..])?;
+        db.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+    }
+    Ok(())
+}
+
+// Compacts every column family, returns the time each one took
+pub fn compact_all(db: &Database) -> Result<Vec<(String, Duration)>, Box<dyn std::error::Error>> {
+    let mut timings = Vec::new();
+    for name in DB::list_cf(&Options::default(), db.path())? {
+        let cf = db.cf_handle(&name).ok_or("Missing column family")?;
+        let started = Instant::now();
+        db.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+        timings.push((name, started.elapsed()));
+    }
+    Ok(timings)
+}
+
+// Offers are keyed by the 16 raw bytes of their ID, and so are the index entries. Every key
+// is derived here from a parsed Uuid, so the casing or hyphens of a string ID never matter.
+pub fn offer_key(id: &Uuid) -> [u8; 16] {
+    *id.as_bytes()
+}
+
+pub fn region_prefix(region_id: i32) -> [u8; 4] {
+    region_id.to_be_bytes()
+}
+
+pub fn region_index_key(region_id: i32, id: &Uuid) -> Vec<u8> {
+    let mut key = Vec::with_capacity(20);
+    key.extend_from_slice(&region_prefix(region_id));
+    key.extend_from_slice(&offer_key(id));
+    key
+}
+
+// Every index entry an offer owns, used to keep the indexes in sync on writes
+pub fn index_keys(offer: &Offer) -> [(&'static str, Vec<u8>); 1] {
+    [(CF_REGION_INDEX, region_index_key(offer.mostSpecificRegionID, &offer.ID))]
+}
+
+pub fn put_index_entries(db: &Database, batch: &mut WriteBatch, offer: &Offer) -> Result<(), Box<dyn std::error::Error>> {
+    for (cf_name, index_key) in index_keys(offer) {
+        let cf = db.cf_handle(cf_name).ok_or("Missing index column family")?;
+        batch.put_cf(&cf, index_key, b"");
+    }
+    Ok(())
+}
+
+pub fn delete_index_entries(db: &Database, batch: &mut WriteBatch, offer: &Offer) -> Result<(), Box<dyn std::error::Error>> {
+    for (cf_name, index_key) in index_keys(offer) {
+        let cf = db.cf_handle(cf_name).ok_or("Missing index column family")?;
+        batch.delete_cf(&cf, index_key);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use std::collections::HashSet;
+
+    // An attempt failing with the given errors in turn, then succeeding, where only
+    // "busy" is transient. Returns the result and the number of attempts.
+    fn retry(retries: u32, errors: &[&'static str]) -> (Result<(), &'static str>, usize) {
+        let retry = WriteRetry {
+            retries,
+            base_delay: Duration::ZERO,
+        };
+        let mut attempts = 0;
+        let result = retry_with(retry, |e| *e == "busy", || {
+            attempts += 1;
+            errors.get(attempts - 1).map_or(Ok(()), |&e| Err(e))
+        });
+        (result, attempts)
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        assert_eq!(retry(3, &["busy", "busy"]), (Ok(()), 3));
+    }
+
+    #[test]
+    fn gives_up_after_the_last_retry() {
+        assert_eq!(retry(2, &["busy", "busy", "busy"]), (Err("busy"), 3));
+    }
+
+    #[test]
+    fn fails_right_away_on_other_errors() {
+        assert_eq!(retry(3, &["corruption", "busy"]), (Err("corruption"), 1));
+    }
+
+    fn sharded() -> DbConfig {
+        DbConfig {
+            shards: 2,
+            ..DbConfig::default()
+        }
+    }
+
+    #[test]
+    fn flush_all_persists_unlogged_writes_of_every_shard() {
+        let dir = TempDir::new();
+        let db = init_db(dir.path(), &sharded()).unwrap();
+        let shard = Shards::new(2).handle(&db, 1).unwrap();
+        let mut batch = WriteBatch::default();
+        batch.put_cf(&shard, b"key", b"value");
+        batch.put_cf(&db.cf_handle(CF_REGION_INDEX).unwrap(), b"index", b"");
+        drop(shard);
+        let mut opts = WriteOptions::default();
+        opts.disable_wal(true);
+        db.write_opt(batch, &opts).unwrap();
+        flush_all(&db).unwrap();
+        drop(db);
+
+        let db = init_db(dir.path(), &sharded()).unwrap();
+        let shard = Shards::new(2).handle(&db, 1).unwrap();
+        assert_eq!(db.get_cf(&shard, b"key").unwrap().as_deref(), Some(&b"value"[..]));
+        let index = db.cf_handle(CF_REGION_INDEX).unwrap();
+        assert!(db.get_cf(&index, b"index").unwrap().is_some());
+    }
+
+    #[test]
+    fn clear_all_empties_every_column_family() {
+        let dir = TempDir::new();
+        let db = init_db(dir.path(), &sharded()).unwrap();
+        let names = DB::list_cf(&Options::default(), db.path()).unwrap();
+        // Both shards and the region index
+        assert_eq!(names.len(), 3);
+        for name in &names {
+            let cf = db.cf_handle(name).unwrap();
+            db.put_cf(&cf, b"first", b"").unwrap();
+            db.put_cf(&cf, [0xff; 20], b"").unwrap();
+        }
+
+        clear_all(&db).unwrap();
+        for name in &names {
+            let cf = db.cf_handle(name).unwrap();
+            assert!(db.iterator_cf(&cf, IteratorMode::Start).next().is_none(), "{} is not empty", name);
+        }
+    }
+
+    #[test]
+    fn regions_spread_evenly_over_the_shards() {
+        let shards = Shards::new(4);
+        let mut per_shard = [0; 4];
+        for region_id in -200..200 {
+            per_shard[shards.of_region(region_id)] += 1;
+        }
+        assert_eq!(per_shard, [100; 4]);
+        assert_eq!(shards.of_region(-1), 3);
+        assert_eq!(Shards::new(0).count(), 1);
+        assert_eq!((Shards::name(0).as_str(), Shards::name(2).as_str()), (CF_OFFERS, "offers_2"));
+    }
+
+    fn column_families(path: &str) -> HashSet<String> {
+        DB::list_cf(&Options::default(), path).unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn init_db_opens_every_declared_column_family() {
+        let dir = TempDir::new();
+        let config = DbConfig {
+            shards: 3,
+            ..DbConfig::default()
+        };
+        let db = init_db(dir.path(), &config).unwrap();
+        for name in (0..3).map(Shards::name).chain(CF_INDEXES.map(String::from)) {
+            assert!(db.cf_handle(&name).is_some(), "{} is missing", name);
+        }
+        drop(db);
+        let expected: HashSet<String> = [CF_OFFERS, "offers_1", "offers_2", CF_REGION_INDEX].map(String::from).into();
+        assert_eq!(column_families(dir.path()), expected);
+
+        // Fewer shards still open the extra ones
+        drop(init_db(dir.path(), &DbConfig::default()).unwrap());
+        assert_eq!(column_families(dir.path()), expected);
+    }
+
+    #[test]
+    fn init_db_drops_the_retired_start_index() {
+        let dir = TempDir::new();
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let names = [CF_OFFERS, CF_REGION_INDEX, CF_DROPPED_REGION_START_INDEX];
+        drop(DBWithThreadMode::<MultiThreaded>::open_cf(&opts, dir.path(), names).unwrap());
+
+        drop(init_db(dir.path(), &DbConfig::default()).unwrap());
+        assert!(!column_families(dir.path()).contains(CF_DROPPED_REGION_START_INDEX));
+    }
+
+    #[test]
+    fn every_form_of_an_id_has_the_same_key() {
+        let id = Uuid::new_v4();
+        let forms = [
+            id.hyphenated().to_string().to_uppercase(),
+            id.simple().to_string(),
+            id.simple().to_string().to_uppercase(),
+            id.urn().to_string(),
+        ];
+        for form in &forms {
+            assert_eq!(offer_key(&Uuid::parse_str(form).unwrap()), offer_key(&id), "{}", form);
+        }
+    }
+}
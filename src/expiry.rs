@@ -0,0 +1,66 @@
+// Background removal of offers whose endDate has passed, they can't match a search anymore
+use crate::cache::SharedCache;
+use crate::db::{delete_index_entries, offer_key, write_with_retry, Database, Shards, WriteRetry};
+use crate::models::Offer;
+use crate::state::{run_write, AppState};
+use rocksdb::WriteBatch;
+use std::time::Duration;
+
+// Runs an expiry pass every interval, forever
+pub async fn run(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately, wait a full interval after startup instead
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+
+        // Expiry is a write like any other, see WriteLock
+        let (db, cache) = (state.db.clone(), state.cache.clone());
+        let (shards, retry) = (state.shards, state.write_retry);
+        let expired = run_write(&state.write_lock, move || {
+            let started = std::time::Instant::now();
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            expire(&db, shards, retry, &cache, now_ms)
+                .map(|expired| (expired, started.elapsed()))
+                .map_err(|e| e.to_string())
+        });
+        match expired.await {
+            Ok((0, _)) => {}
+            Ok((expired, elapsed)) => tracing::info!("Expired {} offers in {:?}", expired, elapsed),
+            Err(e) => tracing::warn!("Expiry failed: {}", e),
+        }
+    }
+}
+
+// Deletes every offer ending before now_ms from RocksDB and the cache, returns how many.
+// Callers must hold the WriteLock.
+pub fn expire(
+    db: &Database,
+    shards: Shards,
+    retry: WriteRetry,
+    cache: &SharedCache,
+    now_ms: i64,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    // The cache holds every offer, so there is no need to scan RocksDB
+    let expired: Vec<Offer> = cache
+        .read()
+        .unwrap()
+        .offers()
+        .filter(|offer| offer.endDate < now_ms)
+        .cloned()
+        .collect();
+    if expired.is_empty() {
+        return Ok(0);
+    }
+
+    let handles = shards.handles(db)?;
+    let mut batch = WriteBatch::default();
+    for offer in &expired {
+        delete_index_entries(db, &mut batch, offer)?;
+        batch.delete_cf(&handles[shards.of_region(offer.mostSpecificRegionID)], offer_key(&offer.ID));
+    }
+    write_with_retry(db, batch, retry)?;
+
+    cache.write().unwrap().remove_many(expired.iter().map(|offer| &offer.ID));
+    Ok(expired.len())
+}
@@ -0,0 +1,140 @@
+use crate::error::ApiError;
+use axum::{
+    async_trait,
+    body::{Bytes, HttpBody},
+    extract::FromRequest,
+    http::{header, HeaderMap, Request, StatusCode},
+    BoxError,
+};
+use serde::de::DeserializeOwned;
+
+// JSON body extractor like axum's Json, but a body that doesn't deserialize is
+// rejected with the path of the offending value, e.g. offers[3].price, as the field
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        if !json_content_type(req.headers()) {
+            return Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected request with `Content-Type: application/json`",
+            ));
+        }
+        // Oversized bodies keep their 413
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(|rejection| ApiError::new(rejection.status(), rejection.body_text()))?;
+
+        let deserializer = &mut serde_json::Deserializer::from_slice(&bytes);
+        serde_path_to_error::deserialize(deserializer).map(JsonBody).map_err(|e| {
+            // The root path is "." and names no field
+            let path = e.path().to_string();
+            let error = ApiError::bad_request(format!("Invalid JSON at {}: {}", path, e.inner()));
+            match path.as_str() {
+                "." => error,
+                _ => error.with_field(path),
+            }
+        })
+    }
+}
+
+// application/json, optionally with parameters, or any +json type
+fn json_content_type(headers: &HeaderMap) -> bool {
+    let content_type = match headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    mime.eq_ignore_ascii_case("application/json") || mime.ends_with("+json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OffersPayload;
+    use crate::testutil::offer;
+    use axum::body::Body;
+    use serde_json::{json, Value};
+
+    async fn extract(body: &Value) -> Result<OffersPayload, ApiError> {
+        let request = Request::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        JsonBody::<OffersPayload>::from_request(request, &()).await.map(|JsonBody(payload)| payload)
+    }
+
+    // Every field of every offer, broken in turn, in a bare and a wrapped list
+    #[tokio::test]
+    async fn every_broken_field_is_named_by_its_path() {
+        let offers: Vec<Value> = (0..3).map(|_| serde_json::to_value(offer()).unwrap()).collect();
+        let fields: Vec<String> = offers[0].as_object().unwrap().keys().cloned().collect();
+        for prefix in ["", "offers"] {
+            // A bare list, or the offers key of a wrapped one
+            let body = |offers: Vec<Value>| match prefix {
+                "" => Value::from(offers),
+                _ => json!({ prefix: offers }),
+            };
+            assert_eq!(extract(&body(offers.clone())).await.unwrap().into_offers().len(), 3);
+            for index in 0..offers.len() {
+                for field in &fields {
+                    let mut wrong_type = offers.clone();
+                    wrong_type[index][field] = json!({ "not": "a value" });
+                    let e = extract(&body(wrong_type)).await.err().unwrap();
+                    assert_eq!(e.status, StatusCode::BAD_REQUEST);
+                    assert_eq!(e.body.field, Some(format!("{}[{}].{}", prefix, index, field)));
+
+                    // A missing field is reported on the offer lacking it
+                    let mut missing = offers.clone();
+                    missing[index].as_object_mut().unwrap().remove(field);
+                    let extracted = extract(&body(missing)).await;
+                    if field == "createdAt" {
+                        assert!(extracted.is_ok());
+                        continue;
+                    }
+                    let e = extracted.err().unwrap();
+                    assert_eq!(e.body.field, Some(format!("{}[{}]", prefix, index)));
+                    assert!(e.body.error.contains(&format!("missing field `{}`", field)), "{}", e.body.error);
+                }
+            }
+        }
+    }
+
+    // Cutting a valid body anywhere is a 400, never a panic
+    #[tokio::test]
+    async fn truncated_bodies_are_bad_requests() {
+        let body = serde_json::to_string(&[offer(), offer()]).unwrap();
+        for end in 0..body.len() {
+            let request = Request::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body[..end].to_string()))
+                .unwrap();
+            let e = JsonBody::<OffersPayload>::from_request(request, &()).await.err().unwrap();
+            assert_eq!(e.status, StatusCode::BAD_REQUEST, "{}", &body[..end]);
+        }
+    }
+
+    #[test]
+    fn json_content_types_include_parameters_and_suffixes() {
+        let accepts = |content_type: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            json_content_type(&headers)
+        };
+        assert!(accepts("application/json"));
+        assert!(accepts("Application/JSON; charset=utf-8"));
+        assert!(accepts("application/problem+json"));
+        assert!(!accepts("text/plain"));
+        assert!(!json_content_type(&HeaderMap::new()));
+    }
+}
@@ -1,276 +1,4107 @@
 use axum::{
-    extract::{Query, Json, State},
-    http::StatusCode,
+    body::{Bytes, StreamBody},
+    extract::{
+        rejection::PathRejection,
+        Path, Query, Json, RawBody, State,
+    },
+    http::{header, HeaderMap, StatusCode},
     response::IntoResponse,
+    BoxError,
 };
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use crate::cache::{FacetCache, FacetKey, OfferCache, SharedCache, SharedFacetCache};
+use crate::codec::{decode_offer, decode_snapshot, encode_offer, encode_snapshot, DataCompression};
 use crate::models::*;
-use crate::db::Database;
+use crate::db::{
+    clear_all, compact_all, delete_index_entries, flush_all, offer_key, put_index_entries, region_index_key, region_prefix,
+    write_with_retry, write_with_retry_wal, Database, Shards, Wal, WriteRetry, CF_REGION_INDEX,
+};
+use crate::error::ApiError;
+use crate::extract::JsonBody;
+use crate::naming::{self, Naming};
+use crate::regions::{RegionTree, SharedRegions};
+use crate::state::{run_write, AdminToken, Limits, WriteLock};
+use crate::seed::{random_offer, Rng};
+use crate::validation::{check_unique_ids, dedup_ids, validate, validate_all};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use futures_util::stream::{self, StreamExt};
+use hyper::body::HttpBody;
 use uuid::Uuid;
-use rocksdb::{DB, WriteBatch};
+use rocksdb::{Direction, IteratorMode, WriteBatch};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+const MILLIS_PER_DAY: i64 = 86_400_000;
+// Larger pages are rejected rather than clamped so clients notice
+const MAX_PAGE_SIZE: u32 = 100;
+// Offers parsed from an NDJSON upload before they are written in one batch
+const NDJSON_BATCH_SIZE: usize = 10_000;
+// Bad lines listed in an NDJSON report, so a body of garbage can't grow it without bound
+const MAX_NDJSON_ERRORS: usize = 100;
+// Longest NDJSON line buffered, an offer is well under 1 KiB. Longer lines are reported and skipped.
+const MAX_NDJSON_LINE_LEN: usize = 64 * 1024;
+// Offers rendered per item of a ?stream=true or export response
+const STREAM_CHUNK_SIZE: usize = 1000;
+// Upper bound of POST /api/offers/seed?count=
+const MAX_SEED_COUNT: u64 = 10_000_000;
 
+#[utoipa::path(
+    get,
+    path = "/api/offers",
+    params(OfferQuery),
+    responses(
+        (status = 200, description = "Matching offers and facets, or with stream=true every matching offer as a JSON line", body = SearchResult),
+        (status = 400, description = "Invalid parameter", body = ErrorBody),
+        (status = 422, description = "Missing parameters", body = ErrorBody),
+    )
+)]
 pub async fn get_offers(
-    State(db): State<Database>,
-    Query(params): Query<HashMap<String, String>>,
+    State(cache): State<SharedCache>,
+    State(regions): State<SharedRegions>,
+    State(facet_cache): State<SharedFacetCache>,
+    State(limits): State<Limits>,
+    Query(query): Query<Vec<(String, String)>>,
 ) -> impl IntoResponse {
-    // Extract and parse query parameters
-    let region_id: i32 = match params.get("regionID") {
-        Some(val) => val.parse().unwrap_or_default(),
-        None => return (StatusCode::BAD_REQUEST, "Missing regionID").into_response(),
-    };
-    let time_range_start: i64 = match params.get("timeRangeStart") {
-        Some(val) => val.parse().unwrap_or_default(),
-        None => return (StatusCode::BAD_REQUEST, "Missing timeRangeStart").into_response(),
-    };
-    let time_range_end: i64 = match params.get("timeRangeEnd") {
-        Some(val) => val.parse().unwrap_or_default(),
-        None => return (StatusCode::BAD_REQUEST, "Missing timeRangeEnd").into_response(),
-    };
-    let number_days: i32 = match params.get("numberDays") {
-        Some(val) => val.parse().unwrap_or_default(),
-        None => return (StatusCode::BAD_REQUEST, "Missing numberDays").into_response(),
-    };
-    let sort_order = match params.get("sortOrder") {
-        Some(val) => val.as_str(),
-        None => return (StatusCode::BAD_REQUEST, "Missing sortOrder").into_response(),
-    };
-    let page: u32 = match params.get("page") {
-        Some(val) => val.parse().unwrap_or_default(),
-        None => return (StatusCode::BAD_REQUEST, "Missing page").into_response(),
-    };
-    let page_size: u32 = match params.get("pageSize") {
-        Some(val) => val.parse().unwrap_or_default(),
-        None => return (StatusCode::BAD_REQUEST, "Missing pageSize").into_response(),
-    };
-    let price_range_width: u32 = match params.get("priceRangeWidth") {
-        Some(val) => val.parse().unwrap_or_default(),
-        None => return (StatusCode::BAD_REQUEST, "Missing priceRangeWidth").into_response(),
-    };
-    let min_free_kilometer_width: u32 = match params.get("minFreeKilometerWidth") {
-        Some(val) => val.parse().unwrap_or_default(),
-        None => return (StatusCode::BAD_REQUEST, "Missing minFreeKilometerWidth").into_response(),
-    };
-
-    // Optional parameters
-    let min_number_seats: Option<u8> = params.get("minNumberSeats").and_then(|v| v.parse().ok());
-    let min_price: Option<u16> = params.get("minPrice").and_then(|v| v.parse().ok());
-    let max_price: Option<u16> = params.get("maxPrice").and_then(|v| v.parse().ok());
-    let car_type: Option<String> = params.get("carType").cloned();
-    let only_vollkasko: Option<bool> = params.get("onlyVollkasko").and_then(|v| v.parse().ok());
-    let min_free_kilometer: Option<u16> = params.get("minFreeKilometer").and_then(|v| v.parse().ok());
-
-    // Build and execute the query
-    let offers = query_offers(
-        &db,
-        region_id,
-        time_range_start,
-        time_range_end,
-        min_number_seats,
-        min_price,
-        max_price,
-        car_type,
-        only_vollkasko,
-        min_free_kilometer,
-        sort_order,
-    );
+    let params = match SearchParams::parse(&query, limits.allow_global_query) {
+        Ok(params) => params,
+        Err(e) => return e.into_response(),
+    };
+    if params.stream {
+        return stream_offers(cache, &regions, &params).into_response();
+    }
+
+    // Offers in the region and time range with the right duration, before any optional filter.
+    // Everything below works on the lightweight FilteredOffer, only the final page copies data.
+    let cache = cache.read().unwrap();
+    let started = Instant::now();
+    let base_offers = query_offers(&cache, &regions, &params);
+
+    // Offers matching every optional filter
+    let mut offers: Vec<FilteredOffer> = base_offers
+        .iter()
+        .copied()
+        .filter(|offer| params.filters.matches(offer))
+        .collect();
+    let scan_time = started.elapsed();
+
+    // A cursor page starts right after the last offer of the previous one, so writes to offers
+    // sorted before the cursor don't shift it the way they shift an offset
+    let total_offers = offers.len() as u32;
+    let page = match params.pagination {
+        Pagination::Offset(page) => page,
+        Pagination::Cursor(cursor) => {
+            if let Some(cursor) = cursor {
+                offers.retain(|offer| cursor.precedes(offer, params.sort_order));
+            }
+            1
+        }
+    };
+
+    // Paginate results, pages past the end are simply empty.
+    // Offers after the requested page are never looked at, so they don't need sorting.
+    let page_size = params.page_size as usize;
+    let start_index = ((page - 1) as usize).saturating_mul(page_size).min(offers.len());
+    let end_index = start_index.saturating_add(page_size).min(offers.len());
+    let started = Instant::now();
+    sort_offers(&mut offers, params.sort_order, end_index);
+    let paginated_offers = offers[start_index..end_index]
+        .iter()
+        .copied()
+        .map(FilteredOffer::to_search_result)
+        .collect();
+    let next_cursor = match params.pagination {
+        Pagination::Cursor(_) if end_index < offers.len() => {
+            Some(Cursor::after(&offers[end_index - 1], params.sort_order).encode())
+        }
+        _ => None,
+    };
+    let sort_time = started.elapsed();
 
-    // Paginate results
-    let start_index = (page - 1) * page_size;
-    let end_index = start_index + page_size;
-    let paginated_offers = offers.get(start_index as usize..end_index as usize)
-        .unwrap_or(&[])
-        .to_vec();
+    // Pagination-only clients can skip the facets entirely
+    let started = Instant::now();
+    let facets = if params.include_aggregations {
+        cached_facets(&facet_cache, &cache, &regions, &query, &params, &base_offers)
+    } else {
+        Facets::default()
+    };
+    let aggregate_time = started.elapsed();
 
-    // Perform aggregations
-    let price_ranges = compute_price_ranges(&offers, price_range_width);
-    let car_type_counts = compute_car_type_counts(&offers);
-    let seats_count = compute_seats_count(&offers);
-    let free_kilometer_range = compute_free_kilometer_ranges(&offers, min_free_kilometer_width);
-    let vollkasko_count = compute_vollkasko_count(&offers);
+    // Counting the scanned offers and stage survivors costs another pass, so only on request
+    let explain = params.explain.then(|| QueryExplain {
+        scanned: scan_windows(
+            &cache,
+            &regions,
+            params.region,
+            params.time_range_start,
+            params.time_range_end,
+            params.days,
+            params.time_match,
+        )
+        .iter()
+        .map(|window| window.len() as u32)
+        .sum(),
+        timeMatches: base_offers.len() as u32,
+        filterStages: params.filters.stages(&base_offers),
+        scanMs: millis(scan_time),
+        sortMs: millis(sort_time),
+        aggregateMs: millis(aggregate_time),
+    });
 
     let result = SearchResult {
         offers: paginated_offers,
-        priceRanges: price_ranges,
-        carTypeCounts: car_type_counts,
-        seatsCount: seats_count,
-        freeKilometerRange: free_kilometer_range,
-        vollkaskoCount: vollkasko_count,
+        totalOffers: total_offers,
+        nextCursor: next_cursor,
+        priceRanges: facets.priceRanges,
+        priceStats: facets.priceStats,
+        carTypeCounts: facets.carTypeCounts,
+        seatsCount: facets.seatsCount,
+        freeKilometerRange: facets.freeKilometerRange,
+        freeKilometerStats: facets.freeKilometerStats,
+        vollkaskoCount: facets.vollkaskoCount,
+        regionCounts: facets.regionCounts,
+        explain,
+    };
+
+    naming::json(params.naming, result)
+}
+
+// Every match as one SearchResultOffer JSON line, sorted but neither paginated nor aggregated.
+// The matches are copied under one read lock like an export, so the lines are exactly the
+// matches at request time and a slow client neither holds the lock nor sees later writes.
+fn stream_offers(cache: SharedCache, regions: &RegionTree, params: &SearchParams) -> impl IntoResponse {
+    let matches: Vec<SearchResultOffer> = {
+        let cache = cache.read().unwrap();
+        let mut offers: Vec<FilteredOffer> = query_offers(&cache, regions, params)
+            .into_iter()
+            .filter(|offer| params.filters.matches(offer))
+            .collect();
+        let len = offers.len();
+        sort_offers(&mut offers, params.sort_order, len);
+        offers.into_iter().map(FilteredOffer::to_search_result).collect()
+    };
+
+    // The stream is only polled when hyper can send more, which gives us backpressure
+    let chunk_count = matches.len().div_ceil(STREAM_CHUNK_SIZE);
+    let lines = stream::iter(0..chunk_count).map(move |chunk| {
+        let end = ((chunk + 1) * STREAM_CHUNK_SIZE).min(matches.len());
+        let mut lines = Vec::new();
+        for offer in &matches[chunk * STREAM_CHUNK_SIZE..end] {
+            serde_json::to_writer(&mut lines, offer)?;
+            lines.push(b'\n');
+        }
+        Ok::<_, serde_json::Error>(lines)
+    });
+
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], StreamBody::new(lines))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/offers/facets",
+    params(OfferQuery),
+    responses(
+        (status = 200, description = "Facets of the matching offers, without the offers", body = Facets),
+        (status = 400, description = "Invalid parameter", body = ErrorBody),
+        (status = 422, description = "Missing parameters", body = ErrorBody),
+    )
+)]
+pub async fn get_facets(
+    State(cache): State<SharedCache>,
+    State(regions): State<SharedRegions>,
+    State(facet_cache): State<SharedFacetCache>,
+    State(limits): State<Limits>,
+    Query(query): Query<Vec<(String, String)>>,
+) -> impl IntoResponse {
+    let params = match SearchParams::parse(&query, limits.allow_global_query) {
+        Ok(params) => params,
+        Err(e) => return e.into_response(),
+    };
+
+    // Same facets as get_offers, skipping sorting and pagination
+    let cache = cache.read().unwrap();
+    let base_offers = query_offers(&cache, &regions, &params);
+    naming::json(params.naming, cached_facets(&facet_cache, &cache, &regions, &query, &params, &base_offers))
+}
+
+// Facets are the same for every page and sort order, so they are remembered per search
+// until one of the searched regions is written. A per-region aggregate can't be narrowed
+// instead: every facet counts the offers matching the time range and all other filters,
+// which takes the offers themselves, and those are what OfferCache already holds.
+fn cached_facets(
+    facet_cache: &FacetCache,
+    cache: &OfferCache,
+    regions: &RegionTree,
+    query: &[(String, String)],
+    params: &SearchParams,
+    base_offers: &[FilteredOffer],
+) -> Facets {
+    let mut key: FacetKey = query
+        .iter()
+        .filter(|(name, _)| {
+            !matches!(
+                name.as_str(),
+                "sortOrder" | "page" | "pageSize" | "pagination" | "cursor" | "includeAggregations" | "naming"
+                    | "explain"
+            )
+        })
+        .cloned()
+        .collect();
+    key.sort();
+    let stamp = cache.stamp(&params.region.ids(cache, regions));
+
+    if let Some(facets) = facet_cache.get(&key, stamp) {
+        return facets;
+    }
+    let facets = compute_facets(base_offers, params);
+    facet_cache.insert(key, stamp, facets.clone());
+    facets
+}
+
+// Aggregations over the offers returned by query_offers, each one ignoring the filter on its own dimension
+fn compute_facets(base_offers: &[FilteredOffer], params: &SearchParams) -> Facets {
+    let offers_without = |dimension: Dimension| -> Vec<FilteredOffer> {
+        base_offers
+            .iter()
+            .copied()
+            .filter(|offer| params.filters.matches_except(offer, dimension))
+            .collect()
     };
 
-    Json(result).into_response()
+    let price_offers = offers_without(Dimension::Price);
+    let free_kilometer_offers = offers_without(Dimension::FreeKilometer);
+    Facets {
+        priceRanges: compute_price_ranges(&price_offers, &params.price_buckets),
+        priceStats: compute_price_stats(&price_offers),
+        carTypeCounts: compute_car_type_counts(&offers_without(Dimension::CarType)),
+        seatsCount: compute_seats_count(&offers_without(Dimension::Seats)),
+        freeKilometerRange: compute_free_kilometer_ranges(&free_kilometer_offers, params.min_free_kilometer_width),
+        freeKilometerStats: compute_free_kilometer_stats(&free_kilometer_offers),
+        vollkaskoCount: compute_vollkasko_count(&offers_without(Dimension::Vollkasko)),
+        // No filter is about the region itself, so this counts the full matches
+        regionCounts: params.include_region_counts.then(|| {
+            let offers: Vec<FilteredOffer> =
+                base_offers.iter().copied().filter(|offer| params.filters.matches(offer)).collect();
+            compute_region_counts(&offers)
+        }),
+    }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/offers",
+    params(
+        ("validate" = Option<bool>, Query, description = "Only validate the offers and report every invalid one"),
+        ("nowal" = Option<bool>, Query, description = "Skip the write-ahead log and flush afterwards, a crash during the request loses the offers"),
+    ),
+    request_body(content = Vec<Offer>, description = "Offers as a bare array or wrapped in {\"offers\": [...]}"),
+    responses(
+        (status = 200, description = "Offers were created, or the validation report", body = ValidationReport),
+        (status = 400, description = "Invalid offers", body = ErrorBody),
+        (status = 413, description = "Body exceeds the configured limit", body = ErrorBody),
+    )
+)]
+#[allow(clippy::too_many_arguments)]
 pub async fn create_offers(
     State(db): State<Database>,
-    Json(payload): Json<HashMap<String, Vec<Offer>>>,
+    State(shards): State<Shards>,
+    State(write_retry): State<WriteRetry>,
+    State(compression): State<DataCompression>,
+    State(cache): State<SharedCache>,
+    State(write_lock): State<WriteLock>,
+    Query(params): Query<HashMap<String, String>>,
+    payload: Result<JsonBody<OffersPayload>, ApiError>,
 ) -> impl IntoResponse {
-    let offers = match payload.get("offers") {
-        Some(offers) if !offers.is_empty() => offers,
-        _ => return (StatusCode::BAD_REQUEST, "Offers list is empty").into_response(),
+    // Malformed offers, e.g. an unknown carType, are a bad request
+    let mut offers = match payload {
+        Ok(JsonBody(payload)) => payload.into_offers(),
+        Err(e) => return e.into_response(),
     };
+    if offers.is_empty() {
+        return ApiError::bad_request("Offers list is empty").with_field("offers").into_response();
+    }
 
-    // Batch insert offers
-    for offer in offers {
-        if let Err(e) = insert_offer(&db, offer.clone()) {
-            eprintln!("Failed to insert offer {}: {}", offer.ID, e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to insert offers").into_response();
+    // A dry run reports every invalid offer, duplicates included, and writes nothing
+    let dry_run = params.get("validate").and_then(|v| v.parse().ok()).unwrap_or(false);
+    if dry_run {
+        return Json(validate_all(&offers)).into_response();
+    }
+
+    if let Err(e) = check_unique_ids(&offers) {
+        return e.into_response();
+    }
+
+    // Validate everything up front so a bad offer doesn't leave a partial insert
+    if let Err(e) = offers.iter().try_for_each(validate) {
+        return e.into_response();
+    }
+
+    // Batch insert offers, a single atomic write for the whole payload
+    let wal = wal_param(&params);
+    run_write(&write_lock, move || {
+        if let Err(e) = insert_offers(&db, shards, write_retry, wal, compression, &mut offers) {
+            tracing::error!("Failed to insert {} offers: {}", offers.len(), e);
+            return ApiError::internal("Failed to insert offers").into_response();
+        }
+        cache.write().unwrap().insert(offers);
+        if let Err(e) = flush_unlogged(&db, wal) {
+            return e.into_response();
+        }
+
+        (StatusCode::OK, "Offers were created").into_response()
+    })
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/offers/{id}",
+    params(("id" = Uuid, Path, description = "Offer ID")),
+    responses(
+        (status = 200, description = "The offer, with its ETag", body = Offer),
+        (status = 304, description = "The offer still matches If-None-Match"),
+        (status = 400, description = "Invalid offer ID", body = ErrorBody),
+        (status = 404, description = "Offer not found", body = ErrorBody),
+    )
+)]
+pub async fn get_offer_by_id(
+    State(db): State<Database>,
+    State(shards): State<Shards>,
+    id: Result<Path<Uuid>, PathRejection>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let id = match id {
+        Ok(Path(id)) => id,
+        Err(_) => return ApiError::bad_request("Invalid offer ID").with_field("id").into_response(),
+    };
+
+    let value = match read_offer_value(&db, shards, &id) {
+        Ok(Some((_, value))) => value,
+        Ok(None) => return ApiError::not_found("Offer not found").into_response(),
+        Err(e) => {
+            tracing::error!("Failed to read offer {}: {}", id, e);
+            return ApiError::internal("Failed to read offer").into_response();
         }
+    };
+
+    // Pollers that already have this version skip the body
+    let etag = offer_etag(&value);
+    if etag_matches(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
     }
 
-    (StatusCode::OK, "Offers were created").into_response()
+    match decode_offer(&value) {
+        Ok(offer) => ([(header::ETAG, etag)], Json(offer)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to decode offer {}: {}", id, e);
+            ApiError::internal("Failed to read offer").into_response()
+        }
+    }
 }
 
-// Helper functions for querying and aggregations
-fn query_offers(
-    db: &Database,
-    region_id: i32,
+#[utoipa::path(
+    post,
+    path = "/api/offers/batch",
+    request_body = OfferIds,
+    responses(
+        (status = 200, description = "The stored offers by ID and the IDs that weren't found", body = OfferBatch),
+        (status = 400, description = "Invalid body or too many IDs", body = ErrorBody),
+    )
+)]
+pub async fn get_offers_batch(
+    State(db): State<Database>,
+    State(shards): State<Shards>,
+    State(limits): State<Limits>,
+    payload: Result<JsonBody<OfferIds>, ApiError>,
+) -> impl IntoResponse {
+    let ids = match payload {
+        Ok(JsonBody(payload)) => payload.ids,
+        Err(e) => return e.into_response(),
+    };
+    if ids.len() > limits.max_batch_ids {
+        let message = format!("At most {} IDs can be fetched at once", limits.max_batch_ids);
+        return ApiError::bad_request(message).with_field("ids").into_response();
+    }
+
+    let offers = match read_offers(&db, shards, &ids) {
+        Ok(offers) => offers,
+        Err(e) => {
+            tracing::error!("Failed to read {} offers: {}", ids.len(), e);
+            return ApiError::internal("Failed to read offers").into_response();
+        }
+    };
+
+    // In request order, an ID asked for twice is reported once
+    let mut missing = Vec::new();
+    for id in ids {
+        if !offers.contains_key(&id) && !missing.contains(&id) {
+            missing.push(id);
+        }
+    }
+
+    Json(OfferBatch { offers, missing }).into_response()
+}
+
+// Strong ETag derived from the stored bytes, they change whenever the offer does
+fn offer_etag(value: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+// If-None-Match holds a list of ETags, possibly weak, or * for any version
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let if_none_match = match headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        Some(if_none_match) => if_none_match,
+        None => return false,
+    };
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/offers/{id}",
+    params(("id" = Uuid, Path, description = "Offer ID")),
+    request_body = OfferPatch,
+    responses(
+        (status = 200, description = "The updated offer", body = Offer),
+        (status = 400, description = "Invalid offer ID or patch", body = ErrorBody),
+        (status = 404, description = "Offer not found", body = ErrorBody),
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn update_offer(
+    State(db): State<Database>,
+    State(shards): State<Shards>,
+    State(write_retry): State<WriteRetry>,
+    State(compression): State<DataCompression>,
+    State(cache): State<SharedCache>,
+    State(write_lock): State<WriteLock>,
+    id: Result<Path<Uuid>, PathRejection>,
+    patch: Result<JsonBody<OfferPatch>, ApiError>,
+) -> impl IntoResponse {
+    let id = match id {
+        Ok(Path(id)) => id,
+        Err(_) => return ApiError::bad_request("Invalid offer ID").with_field("id").into_response(),
+    };
+    let patch = match patch {
+        Ok(JsonBody(patch)) => patch,
+        Err(e) => return e.into_response(),
+    };
+
+    // Read, patch and write back under the write lock so no other write interleaves.
+    // The upsert moves the index entries if indexed fields changed.
+    run_write(&write_lock, move || {
+        let mut updated = match read_offer(&db, shards, &id) {
+            Ok(Some(offer)) => offer,
+            Ok(None) => return ApiError::not_found("Offer not found").into_response(),
+            Err(e) => {
+                tracing::error!("Failed to read offer {}: {}", id, e);
+                return ApiError::internal("Failed to read offer").into_response();
+            }
+        };
+
+        patch.apply(&mut updated);
+        if let Err(e) = validate(&updated) {
+            return e.into_response();
+        }
+
+        let updated = match insert_offer(&db, shards, write_retry, compression, updated) {
+            Ok(updated) => updated,
+            Err(e) => {
+                tracing::error!("Failed to update offer {}: {}", id, e);
+                return ApiError::internal("Failed to update offer").into_response();
+            }
+        };
+        cache.write().unwrap().insert([updated.clone()]);
+
+        Json(updated).into_response()
+    })
+    .await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/offers/{id}",
+    params(("id" = Uuid, Path, description = "Offer ID")),
+    responses(
+        (status = 204, description = "Offer was deleted"),
+        (status = 400, description = "Invalid offer ID", body = ErrorBody),
+    )
+)]
+pub async fn delete_offer(
+    State(db): State<Database>,
+    State(shards): State<Shards>,
+    State(write_retry): State<WriteRetry>,
+    State(cache): State<SharedCache>,
+    State(write_lock): State<WriteLock>,
+    id: Result<Path<Uuid>, PathRejection>,
+) -> impl IntoResponse {
+    let id = match id {
+        Ok(Path(id)) => id,
+        Err(_) => return ApiError::bad_request("Invalid offer ID").with_field("id").into_response(),
+    };
+
+    // Deleting a missing key is a no-op in RocksDB, so this is idempotent
+    run_write(&write_lock, move || {
+        if let Err(e) = delete_offer_by_id(&db, shards, write_retry, &id) {
+            tracing::error!("Failed to delete offer {}: {}", id, e);
+            return ApiError::internal("Failed to delete offer").into_response();
+        }
+        cache.write().unwrap().remove(&id);
+
+        StatusCode::NO_CONTENT.into_response()
+    })
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses(
+        (status = 200, description = "Database is responsive"),
+        (status = 503, description = "Database unavailable", body = ErrorBody),
+    )
+)]
+pub async fn health(State(db): State<Database>) -> impl IntoResponse {
+    // A point lookup is enough to check the store responds
+    if let Err(e) = db.get(b"__health__") {
+        tracing::error!("Health check failed: {}", e);
+        return ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable").into_response();
+    }
+
+    Json(serde_json::json!({ "status": "ok" })).into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/compact",
+    params(("x-admin-token" = String, Header, description = "The configured ADMIN_TOKEN")),
+    responses(
+        (status = 200, description = "Every column family was compacted", body = CompactionReport),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 500, description = "Compaction failed", body = ErrorBody),
+    )
+)]
+pub async fn compact_database(
+    State(db): State<Database>,
+    State(admin_token): State<AdminToken>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !admin_token.accepts(&headers) {
+        return ApiError::new(StatusCode::UNAUTHORIZED, "Missing or invalid admin token").into_response();
+    }
+
+    // Reclaims the space of deleted offers, e.g. after expiry, without deleting anything.
+    // Compaction runs concurrently with reads and writes, so no lock is taken.
+    let started = std::time::Instant::now();
+    let timings = match compact_all(&db) {
+        Ok(timings) => timings,
+        Err(e) => {
+            tracing::error!("Failed to compact database: {}", e);
+            return ApiError::internal("Failed to compact database").into_response();
+        }
+    };
+
+    Json(CompactionReport {
+        elapsedMs: started.elapsed().as_millis() as u64,
+        columnFamilies: timings
+            .into_iter()
+            .map(|(name, elapsed)| ColumnFamilyCompaction {
+                name,
+                elapsedMs: elapsed.as_millis() as u64,
+            })
+            .collect(),
+    })
+    .into_response()
+}
+
+// Fallback of every method router. axum adds the Allow header listing the methods
+// of the path, including ones merged in by a later route() call.
+pub async fn method_not_allowed() -> impl IntoResponse {
+    ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+}
+
+// Error handler of the load shedding layer, its only error is being over the concurrency limit
+pub async fn overloaded(_: BoxError) -> impl IntoResponse {
+    ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "Too many requests in flight, try again later")
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/offers/count",
+    params(OfferQuery),
+    responses(
+        (status = 200, description = "Number of matching offers"),
+        (status = 400, description = "Invalid parameter", body = ErrorBody),
+        (status = 422, description = "Missing parameters", body = ErrorBody),
+    )
+)]
+pub async fn count_offers(
+    State(cache): State<SharedCache>,
+    State(regions): State<SharedRegions>,
+    State(limits): State<Limits>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> impl IntoResponse {
+    let params = match SearchParams::parse(&params, limits.allow_global_query) {
+        Ok(params) => params,
+        Err(e) => return e.into_response(),
+    };
+
+    let count = count_matching(&cache.read().unwrap(), &regions, &params);
+    Json(serde_json::json!({ "count": count })).into_response()
+}
+
+#[utoipa::path(
+    head,
+    path = "/api/offers",
+    params(OfferQuery),
+    responses(
+        (status = 200, description = "Number of matching offers in X-Total-Count, without a body"),
+        (status = 400, description = "Invalid parameter"),
+        (status = 422, description = "Missing parameters"),
+    )
+)]
+pub async fn head_offers(
+    State(cache): State<SharedCache>,
+    State(regions): State<SharedRegions>,
+    State(limits): State<Limits>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> impl IntoResponse {
+    let params = match SearchParams::parse(&params, limits.allow_global_query) {
+        Ok(params) => params,
+        Err(e) => return e.into_response(),
+    };
+
+    let count = count_matching(&cache.read().unwrap(), &regions, &params);
+    (StatusCode::OK, [("x-total-count", count.to_string())]).into_response()
+}
+
+// Same filtering as get_offers, without sorting, pagination or aggregations
+fn count_matching(cache: &OfferCache, regions: &RegionTree, params: &SearchParams) -> usize {
+    let base_offers = query_offers(cache, regions, params);
+    base_offers.iter().filter(|offer| params.filters.matches(offer)).count()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/offers/stats",
+    responses((status = 200, description = "Summary of every stored offer", body = OfferStats))
+)]
+pub async fn offer_stats(State(cache): State<SharedCache>) -> impl IntoResponse {
+    let cache = cache.read().unwrap();
+    Json(compute_offer_stats(&cache)).into_response()
+}
+
+// Longest a client may wait for a change
+const MAX_VERSION_WAIT_SECS: u64 = 60;
+
+#[utoipa::path(
+    get,
+    path = "/api/offers/version",
+    params(
+        ("wait" = Option<u64>, Query, description = "With a matching If-None-Match, seconds to wait for a change before answering 304, at most 60"),
+    ),
+    responses(
+        (status = 200, description = "Current generation of the dataset, bumped by every write", body = OfferVersion),
+        (status = 304, description = "Still the generation in If-None-Match"),
+        (status = 400, description = "Invalid wait", body = ErrorBody),
+    )
+)]
+pub async fn offer_version(
+    State(cache): State<SharedCache>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let wait = match param_or(&params, "wait", 0u64) {
+        Ok(wait) => Duration::from_secs(wait.min(MAX_VERSION_WAIT_SECS)),
+        Err(e) => return e.into_response(),
+    };
+
+    // Subscribe before reading the generation so a write in between still wakes us
+    let (epoch, mut changes) = {
+        let cache = cache.read().unwrap();
+        (cache.epoch(), cache.subscribe())
+    };
+    let mut generation = *changes.borrow_and_update();
+    if etag_matches(&headers, &generation_etag(epoch, generation)) && !wait.is_zero() {
+        // Gives up after the wait, the client then polls again
+        if let Ok(Ok(())) = tokio::time::timeout(wait, changes.changed()).await {
+            generation = *changes.borrow();
+        }
+    }
+
+    let etag = generation_etag(epoch, generation);
+    if etag_matches(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    ([(header::ETAG, etag)], Json(OfferVersion { generation })).into_response()
+}
+
+// Generations start over with the process, the epoch keeps an ETag of an earlier run from
+// matching once this one reaches the same generation
+fn generation_etag(epoch: u64, generation: u64) -> String {
+    format!("\"{:016x}-{}\"", epoch, generation)
+}
+
+// Single pass over the cache accumulating every statistic at once
+fn compute_offer_stats(cache: &OfferCache) -> OfferStats {
+    let mut total_offers: u32 = 0;
+    let mut min_price: Option<u16> = None;
+    let mut max_price: Option<u16> = None;
+    let mut price_sum: u64 = 0;
+    let mut regions = HashSet::new();
+    let mut car_type_counts = CarTypeCount {
+        small: 0,
+        sports: 0,
+        luxury: 0,
+        family: 0,
+    };
+    for offer in cache.offers() {
+        total_offers += 1;
+        min_price = Some(min_price.map_or(offer.price, |min| min.min(offer.price)));
+        max_price = Some(max_price.map_or(offer.price, |max| max.max(offer.price)));
+        price_sum += offer.price as u64;
+        regions.insert(offer.mostSpecificRegionID);
+        match offer.carType {
+            CarType::Small => car_type_counts.small += 1,
+            CarType::Sports => car_type_counts.sports += 1,
+            CarType::Luxury => car_type_counts.luxury += 1,
+            CarType::Family => car_type_counts.family += 1,
+        }
+    }
+
+    OfferStats {
+        totalOffers: total_offers,
+        minPrice: min_price,
+        maxPrice: max_price,
+        avgPrice: (total_offers > 0).then(|| price_sum as f64 / total_offers as f64),
+        distinctRegions: regions.len() as u32,
+        carTypeCounts: car_type_counts,
+    }
+}
+
+// Parsed query parameters shared by the search endpoints
+#[derive(Clone, Debug)]
+struct SearchParams {
+    region: RegionScope,
     time_range_start: i64,
     time_range_end: i64,
+    days: DaysFilter,
+    time_match: TimeMatch,
+    sort_order: SortOrder,
+    pagination: Pagination,
+    page_size: u32,
+    price_buckets: PriceBuckets,
+    min_free_kilometer_width: u32,
+    filters: Filters,
+    data_prefix: Option<Vec<u8>>,
+    include_aggregations: bool,
+    include_region_counts: bool,
+    explain: bool,
+    stream: bool,
+    naming: Naming,
+}
+
+impl SearchParams {
+    // Takes the raw pairs since carType may be repeated, otherwise the last value wins
+    fn parse(pairs: &[(String, String)], allow_global: bool) -> Result<Self, ApiError> {
+        let params: HashMap<String, String> = pairs.iter().cloned().collect();
+
+        // A duration range replaces the exact numberDays
+        let has_days_range = params.contains_key("minNumberDays") || params.contains_key("maxNumberDays");
+
+        // Extract and parse query parameters, reporting every missing one at once
+        let mut required = RequiredParams::new(&params);
+        // Without a regionID, or with regionID=all, every region is searched if that is enabled
+        let region = match params.get("regionID").map(String::as_str) {
+            Some("all") | None if allow_global => RegionScope::All,
+            Some("all") => {
+                return Err(ApiError::bad_request("Global queries are disabled, see ALLOW_GLOBAL_QUERY")
+                    .with_field("regionID"))
+            }
+            _ => RegionScope::Region(required.get("regionID")),
+        };
+        let time_range_start: i64 = required.get("timeRangeStart");
+        let time_range_end: i64 = required.get("timeRangeEnd");
+        let number_days: i32 = if has_days_range { 0 } else { required.get("numberDays") };
+        let sort_order: SortOrder = required.get("sortOrder");
+        // pagination=cursor, or a cursor of a previous page, replaces page
+        let pagination = match (params.get("pagination").map(String::as_str), params.get("cursor")) {
+            (Some("cursor"), None) => Pagination::Cursor(None),
+            (Some("cursor") | None, Some(cursor)) => Pagination::Cursor(Some(Cursor::decode(cursor)?)),
+            (Some("offset") | None, None) => Pagination::Offset(required.get("page")),
+            (Some("offset"), Some(_)) => {
+                return Err(ApiError::bad_request("cursor can't be combined with pagination=offset")
+                    .with_fields(vec!["cursor".to_string(), "pagination".to_string()]))
+            }
+            (Some(_), _) => {
+                return Err(ApiError::bad_request("pagination must be offset or cursor").with_field("pagination"))
+            }
+        };
+        let page_size: u32 = required.get("pageSize");
+        let price_range_width: u32 = required.get("priceRangeWidth");
+        let min_free_kilometer_width: u32 = required.get("minFreeKilometerWidth");
+        required.finish()?;
+
+        // An inverted range or an empty rental would just match nothing, which hides client bugs
+        if time_range_start > time_range_end {
+            return Err(ApiError::bad_request("timeRangeStart must not be after timeRangeEnd")
+                .with_fields(vec!["timeRangeStart".to_string(), "timeRangeEnd".to_string()]));
+        }
+        let days = if has_days_range {
+            DaysFilter::parse_range(&params)?
+        } else if number_days <= 0 {
+            return Err(ApiError::bad_request("numberDays must be at least 1").with_field("numberDays"));
+        } else {
+            DaysFilter::Exact(number_days)
+        };
+        if let Pagination::Offset(0) = pagination {
+            return Err(ApiError::bad_request("page must be at least 1").with_field("page"));
+        }
+        if page_size == 0 {
+            return Err(ApiError::bad_request("pageSize must be at least 1").with_field("pageSize"));
+        }
+        if page_size > MAX_PAGE_SIZE {
+            return Err(ApiError::bad_request(format!("pageSize must be at most {}", MAX_PAGE_SIZE))
+                .with_field("pageSize"));
+        }
+        if price_range_width == 0 {
+            return Err(ApiError::bad_request("priceRangeWidth must be at least 1").with_field("priceRangeWidth"));
+        }
+        if min_free_kilometer_width == 0 {
+            return Err(ApiError::bad_request("minFreeKilometerWidth must be at least 1")
+                .with_field("minFreeKilometerWidth"));
+        }
+
+        // Optional parameters
+        let min_number_seats: Option<u8> = params.get("minNumberSeats").and_then(|v| v.parse().ok());
+        let number_seats: Option<u8> = optional_param(&params, "numberSeats")?;
+        // An exact and a minimum seat count would silently override each other, reject both
+        if min_number_seats.is_some() && number_seats.is_some() {
+            return Err(ApiError::bad_request("numberSeats and minNumberSeats can't be combined")
+                .with_fields(vec!["numberSeats".to_string(), "minNumberSeats".to_string()]));
+        }
+        let min_price: Option<u16> = params.get("minPrice").and_then(|v| v.parse().ok());
+        let max_price: Option<u16> = params.get("maxPrice").and_then(|v| v.parse().ok());
+        // carType=small,luxury and carType=small&carType=luxury both select several types
+        let mut car_type: Option<CarTypeSet> = None;
+        for val in pairs.iter().filter(|(key, _)| key == "carType").flat_map(|(_, val)| val.split(',')) {
+            let parsed: CarType = val.parse().map_err(|e: String| ApiError::bad_request(e).with_field("carType"))?;
+            car_type.get_or_insert_with(CarTypeSet::default).insert(parsed);
+        }
+        let only_vollkasko: Option<bool> = params.get("onlyVollkasko").and_then(|v| v.parse().ok());
+        let min_free_kilometer: Option<u16> = params.get("minFreeKilometer").and_then(|v| v.parse().ok());
+        let max_free_kilometer: Option<u16> = params.get("maxFreeKilometer").and_then(|v| v.parse().ok());
+        // Fixed price bands replace the priceRangeWidth buckets
+        let price_buckets = match params.get("priceBuckets") {
+            Some(val) => PriceBuckets::parse_fixed(val)?,
+            None => PriceBuckets::Width(price_range_width),
+        };
+        // Checked while scanning like the time range, so it also narrows every facet
+        let data_prefix = match params.get("dataPrefix") {
+            Some(val) => match STANDARD.decode(val) {
+                Ok(prefix) => Some(prefix),
+                Err(_) => return Err(ApiError::bad_request("dataPrefix must be base64").with_field("dataPrefix")),
+            },
+            None => None,
+        };
+        let include_aggregations = params
+            .get("includeAggregations")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(true);
+        let include_region_counts = params
+            .get("includeRegionCounts")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(false);
+        let explain = params.get("explain").and_then(|v| v.parse().ok()).unwrap_or(false);
+        let stream = params.get("stream").and_then(|v| v.parse().ok()).unwrap_or(false);
+        let naming: Naming = param_or(&params, "naming", Naming::Camel)?;
+        let time_match: TimeMatch = param_or(&params, "timeMatch", TimeMatch::Contained)?;
+
+        Ok(SearchParams {
+            region,
+            time_range_start,
+            time_range_end,
+            days,
+            time_match,
+            sort_order,
+            pagination,
+            page_size,
+            price_buckets,
+            min_free_kilometer_width,
+            filters: Filters {
+                min_number_seats,
+                number_seats,
+                min_price,
+                max_price,
+                car_type,
+                only_vollkasko,
+                min_free_kilometer,
+                max_free_kilometer,
+            },
+            data_prefix,
+            include_aggregations,
+            include_region_counts,
+            explain,
+            stream,
+            naming,
+        })
+    }
+}
+
+// Optional filters of a search, an unset one lets every offer through
+#[derive(Clone, Copy, Debug)]
+struct Filters {
     min_number_seats: Option<u8>,
+    number_seats: Option<u8>,
     min_price: Option<u16>,
     max_price: Option<u16>,
-    car_type: Option<String>,
+    car_type: Option<CarTypeSet>,
     only_vollkasko: Option<bool>,
     min_free_kilometer: Option<u16>,
-    sort_order: &str,
-) -> Vec<SearchResultOffer> {
-    // Implement the query logic here
-    let mut offers = Vec::new();
+    max_free_kilometer: Option<u16>,
+}
 
-    let iter = db.iterator(rocksdb::IteratorMode::Start);
-    for item in iter {
-        let (_, value) = item.unwrap();
-        let offer: Offer = serde_json::from_slice(&value).unwrap();
+// What a filter, and the facet aggregating it, is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Dimension {
+    Seats,
+    Price,
+    CarType,
+    Vollkasko,
+    FreeKilometer,
+}
 
-        // Apply filters
-        if offer.mostSpecificRegionID != region_id {
-            continue;
-        }
-        if offer.startDate > time_range_end || offer.endDate < time_range_start {
-            continue;
+impl Dimension {
+    const ALL: [Dimension; 5] = [
+        Dimension::Seats,
+        Dimension::Price,
+        Dimension::CarType,
+        Dimension::Vollkasko,
+        Dimension::FreeKilometer,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Dimension::Seats => "seats",
+            Dimension::Price => "price",
+            Dimension::CarType => "carType",
+            Dimension::Vollkasko => "vollkasko",
+            Dimension::FreeKilometer => "freeKilometer",
         }
-        if let Some(min_seats) = min_number_seats {
-            if offer.numberSeats < min_seats {
-                continue;
+    }
+}
+
+impl Filters {
+    // Whether the offer passes every filter
+    fn matches(&self, offer: &FilteredOffer) -> bool {
+        self.matches_on(offer, None)
+    }
+
+    // Every filter but the one on `skip`, facets count what picking another value would give
+    fn matches_except(&self, offer: &FilteredOffer, skip: Dimension) -> bool {
+        self.matches_on(offer, Some(skip))
+    }
+
+    fn matches_on(&self, offer: &FilteredOffer, skip: Option<Dimension>) -> bool {
+        Dimension::ALL
+            .iter()
+            .all(|&dimension| skip == Some(dimension) || self.matches_dimension(offer, dimension))
+    }
+
+    fn matches_dimension(&self, offer: &FilteredOffer, dimension: Dimension) -> bool {
+        match dimension {
+            Dimension::Seats => matches_seats(offer, self.min_number_seats, self.number_seats),
+            Dimension::Price => matches_price(offer, self.min_price, self.max_price),
+            Dimension::CarType => matches_car_type(offer, self.car_type),
+            Dimension::Vollkasko => matches_vollkasko(offer, self.only_vollkasko),
+            Dimension::FreeKilometer => {
+                matches_free_kilometer(offer, self.min_free_kilometer, self.max_free_kilometer)
             }
         }
-        if let Some(min_p) = min_price {
-            if offer.price < min_p {
-                continue;
-            }
+    }
+
+    // Offers left after applying one filter after the other, in Dimension::ALL order
+    fn stages(&self, offers: &[FilteredOffer]) -> Vec<FilterStage> {
+        let mut remaining: Vec<&FilteredOffer> = offers.iter().collect();
+        Dimension::ALL
+            .iter()
+            .map(|&dimension| {
+                remaining.retain(|offer| self.matches_dimension(offer, dimension));
+                FilterStage {
+                    filter: dimension.name().to_string(),
+                    remaining: remaining.len() as u32,
+                }
+            })
+            .collect()
+    }
+}
+
+// Rental duration in whole days, either numberDays or minNumberDays..=maxNumberDays
+#[derive(Clone, Copy, Debug)]
+enum DaysFilter {
+    Exact(i32),
+    Range(i32, i32),
+}
+
+impl DaysFilter {
+    // Either bound may be left out, an exact numberDays on top would be ambiguous
+    fn parse_range(params: &HashMap<String, String>) -> Result<Self, ApiError> {
+        if params.contains_key("numberDays") {
+            return Err(ApiError::bad_request("numberDays can't be combined with minNumberDays or maxNumberDays")
+                .with_fields(vec![
+                    "numberDays".to_string(),
+                    "minNumberDays".to_string(),
+                    "maxNumberDays".to_string(),
+                ]));
         }
-        if let Some(max_p) = max_price {
-            if offer.price >= max_p {
-                continue;
-            }
+        let min_days = param_or(params, "minNumberDays", 1)?;
+        let max_days = param_or(params, "maxNumberDays", i32::MAX)?;
+        if min_days <= 0 {
+            return Err(ApiError::bad_request("minNumberDays must be at least 1").with_field("minNumberDays"));
         }
-        if let Some(ref ct) = car_type {
-            if &offer.carType != ct {
-                continue;
-            }
+        if min_days > max_days {
+            return Err(ApiError::bad_request("minNumberDays must not be above maxNumberDays")
+                .with_fields(vec!["minNumberDays".to_string(), "maxNumberDays".to_string()]));
         }
-        if let Some(only_vk) = only_vollkasko {
-            if offer.hasVollkasko != only_vk {
-                continue;
-            }
+        Ok(DaysFilter::Range(min_days, max_days))
+    }
+
+    // Shortest duration that can match, in milliseconds
+    fn min_duration(self) -> i64 {
+        match self {
+            DaysFilter::Exact(days) | DaysFilter::Range(days, _) => days as i64 * MILLIS_PER_DAY,
         }
-        if let Some(min_fk) = min_free_kilometer {
-            if offer.freeKilometers < min_fk {
-                continue;
-            }
+    }
+
+    // Longest duration that can match, in milliseconds
+    fn max_duration(self) -> i64 {
+        match self {
+            DaysFilter::Exact(days) => days as i64 * MILLIS_PER_DAY,
+            // Whole days are rounded down, so up to a day less a millisecond more still matches
+            DaysFilter::Range(_, days) => (days as i64 + 1) * MILLIS_PER_DAY - 1,
         }
+    }
 
-        // Add to results
-        offers.push(SearchResultOffer {
-            ID: offer.ID,
-            data: offer.data.clone(),
-        });
+    // An exact count has to match to the millisecond, a range counts whole days
+    fn matches(self, duration: i64) -> bool {
+        match self {
+            DaysFilter::Exact(days) => duration == days as i64 * MILLIS_PER_DAY,
+            DaysFilter::Range(min_days, max_days) => {
+                (min_days as i64..=max_days as i64).contains(&(duration / MILLIS_PER_DAY))
+            }
+        }
     }
+}
+
+// How an offer has to relate to timeRangeStart..timeRangeEnd
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TimeMatch {
+    // Starts and ends inside the range
+    Contained,
+    // Shares at least an instant with the range
+    Overlap,
+}
 
+impl FromStr for TimeMatch {
+    type Err = String;
 
-    // Sort offers
-    offers.sort_by(|a, b| {
-        match sort_order {
-            "price-asc" => a.ID.cmp(&b.ID),
-            "price-desc" => b.ID.cmp(&a.ID),
-            _ => a.ID.cmp(&b.ID),
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "contained" => Ok(TimeMatch::Contained),
+            "overlap" => Ok(TimeMatch::Overlap),
+            other => Err(format!("Unknown time match {}", other)),
         }
-    });
+    }
+}
 
-    offers
+// Bucketing of the priceRanges facet
+#[derive(Clone, Debug)]
+enum PriceBuckets {
+    // Buckets of priceRangeWidth starting at 0, only non-empty ones are returned
+    Width(u32),
+    // Bands between consecutive boundaries of priceBuckets, every band is returned
+    Fixed(Vec<u32>),
 }
 
-pub async fn cleanup_data(State(db): State<Database>) -> impl IntoResponse {
-    // Get the default column family handle
-    let cf_handle = match db.cf_handle("default") {
-        Some(handle) => handle,
-        None => {
-            eprintln!("Failed to get default column family");
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to clean up data").into_response();
+impl PriceBuckets {
+    // priceBuckets=0,5000,10000 gives the bands [0, 5000) and [5000, 10000)
+    fn parse_fixed(val: &str) -> Result<Self, ApiError> {
+        let invalid = |message: &str| ApiError::bad_request(message).with_field("priceBuckets");
+        let boundaries = val
+            .split(',')
+            .map(|boundary| boundary.trim().parse())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| invalid("priceBuckets must be a comma separated list of prices"))?;
+        if boundaries.len() < 2 {
+            return Err(invalid("priceBuckets needs at least two boundaries"));
         }
-    };
-
-    // Delete all keys in the default column family
-    if let Err(e) = db.delete_range_cf(&cf_handle, &b""[..], &b"\xFF"[..]) {
-        eprintln!("Failed to delete range: {}", e);
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to clean up data").into_response();
+        if boundaries.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(invalid("priceBuckets boundaries must be strictly increasing"));
+        }
+        Ok(PriceBuckets::Fixed(boundaries))
     }
+}
 
-    // Optionally, force compaction to reclaim disk space
-    db.compact_range_cf(&cf_handle, None::<&[u8]>, None::<&[u8]>);
+// Car types selected by the carType filter, one bit per type
+#[derive(Clone, Copy, Debug, Default)]
+struct CarTypeSet(u8);
 
-    (StatusCode::OK, "Data was cleaned up").into_response()
-}
+impl CarTypeSet {
+    fn insert(&mut self, car_type: CarType) {
+        self.0 |= 1 << car_type as u8;
+    }
 
-// TODO implement batch insert
-fn insert_offers(db: &Database, offers: &[Offer]) -> Result<(), Box<dyn std::error::Error>> {
-    let mut batch = WriteBatch::default();
-    for offer in offers {
-        let key = offer.ID.as_bytes();
-        let value = serde_json::to_vec(offer)?;
-        batch.put(key, value);
+    fn contains(self, car_type: CarType) -> bool {
+        self.0 & (1 << car_type as u8) != 0
     }
-    db.write(batch)?;
-    Ok(())
 }
 
-fn insert_offer(db: &Database, offer: Offer) -> Result<(), Box<dyn std::error::Error>> {
-    let key = offer.ID.as_bytes();
-    let value = serde_json::to_vec(&offer)?;
-    db.put(key, value)?;
-    Ok(())
+#[derive(Clone, Copy, Debug)]
+enum Pagination {
+    // 1-based page number
+    Offset(u32),
+    // The first page, or the one after a cursor
+    Cursor(Option<Cursor>),
 }
 
-// Placeholder aggregation functions
-fn compute_price_ranges(offers: &Vec<SearchResultOffer>, width: u32) -> Vec<PriceRange> {
-    // Implement aggregation logic
-    Vec::new()
+// Position of the last offer of a page in the sort order: its sort key and ID.
+// Clients get it as URL safe base64 of the big endian key followed by the ID.
+#[derive(Clone, Copy, Debug)]
+struct Cursor {
+    key: i64,
+    id: Uuid,
 }
 
-fn compute_car_type_counts(offers: &Vec<SearchResultOffer>) -> CarTypeCount {
-    // Implement aggregation logic
-    CarTypeCount {
-        small: 0,
-        sports: 0,
-        luxury: 0,
-        family: 0,
+impl Cursor {
+    fn after(offer: &FilteredOffer, sort_order: SortOrder) -> Self {
+        Cursor {
+            key: sort_key(offer, sort_order),
+            id: offer.ID,
+        }
+    }
+
+    fn encode(self) -> String {
+        let mut bytes = [0u8; 24];
+        bytes[..8].copy_from_slice(&self.key.to_be_bytes());
+        bytes[8..].copy_from_slice(self.id.as_bytes());
+        URL_SAFE_NO_PAD.encode(bytes)
     }
+
+    fn decode(cursor: &str) -> Result<Self, ApiError> {
+        let invalid = || ApiError::bad_request("Invalid cursor").with_field("cursor");
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+        if bytes.len() != 24 {
+            return Err(invalid());
+        }
+        Ok(Cursor {
+            key: i64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            id: Uuid::from_slice(&bytes[8..]).map_err(|_| invalid())?,
+        })
+    }
+
+    // Whether the offer comes after the cursor, and so on a later page
+    fn precedes(self, offer: &FilteredOffer, sort_order: SortOrder) -> bool {
+        let offer_key = (sort_key(offer, sort_order), offer.ID);
+        compare_sort_keys(sort_order, (self.key, self.id), offer_key).is_lt()
+    }
+}
+
+// Regions a search covers
+#[derive(Clone, Copy, Debug)]
+enum RegionScope {
+    // The region and every region below it
+    Region(i32),
+    // Every region, see ALLOW_GLOBAL_QUERY
+    All,
 }
 
-fn compute_seats_count(offers: &Vec<SearchResultOffer>) -> Vec<SeatsCount> {
-    // Implement aggregation logic
-    Vec::new()
+impl RegionScope {
+    // Sorted for All so the scan order, and with it the order of ties, is stable
+    fn ids(self, cache: &OfferCache, regions: &RegionTree) -> Vec<i32> {
+        match self {
+            RegionScope::Region(region_id) => std::iter::once(region_id)
+                .chain(regions.descendants(region_id).iter().copied())
+                .collect(),
+            RegionScope::All => {
+                let mut ids: Vec<i32> = cache.region_ids().collect();
+                ids.sort_unstable();
+                ids
+            }
+        }
+    }
 }
 
-fn compute_free_kilometer_ranges(offers: &Vec<SearchResultOffer>, width: u32) -> Vec<FreeKilometerRange> {
-    // Implement aggregation logic
-    Vec::new()
+// Required query parameters, problems are collected instead of failing on the first
+struct RequiredParams<'a> {
+    params: &'a HashMap<String, String>,
+    missing: Vec<String>,
+    invalid: Vec<String>,
 }
 
-fn compute_vollkasko_count(offers: &Vec<SearchResultOffer>) -> VollkaskoCount {
-    // Implement aggregation logic
-    VollkaskoCount {
-        trueCount: 0,
-        falseCount: 0,
+impl<'a> RequiredParams<'a> {
+    fn new(params: &'a HashMap<String, String>) -> Self {
+        RequiredParams {
+            params,
+            missing: Vec::new(),
+            invalid: Vec::new(),
+        }
     }
-}
\ No newline at end of file
+
+    // Returns the parsed value, or a placeholder default that finish() turns into an error
+    fn get<T: FromStr + Default>(&mut self, name: &str) -> T {
+        match self.params.get(name).map(|val| val.parse()) {
+            Some(Ok(val)) => val,
+            Some(Err(_)) => {
+                self.invalid.push(name.to_string());
+                T::default()
+            }
+            None => {
+                self.missing.push(name.to_string());
+                T::default()
+            }
+        }
+    }
+
+    // Missing parameters are a 422, present but unparseable ones a 400.
+    // With both it is a 422 naming every one of them.
+    fn finish(mut self) -> Result<(), ApiError> {
+        if !self.missing.is_empty() {
+            let mut error = format!("Missing {}", self.missing.join(", "));
+            if !self.invalid.is_empty() {
+                error += &format!("; invalid {}", self.invalid.join(", "));
+            }
+            self.missing.append(&mut self.invalid);
+            return Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, error).with_fields(self.missing));
+        }
+        if !self.invalid.is_empty() {
+            return Err(ApiError::bad_request(format!("Invalid {}", self.invalid.join(", ")))
+                .with_fields(self.invalid));
+        }
+        Ok(())
+    }
+}
+
+// Helper functions for querying and aggregations
+// Offers query_offers checked against the search on the current thread, counted for the tests
+#[cfg(test)]
+thread_local! {
+    static VISITED_OFFERS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn query_offers<'a>(cache: &'a OfferCache, regions: &RegionTree, params: &SearchParams) -> Vec<FilteredOffer<'a>> {
+    let fits = |offer: &&Offer| {
+        #[cfg(test)]
+        VISITED_OFFERS.with(|visited| visited.set(visited.get() + 1));
+        let in_range = match params.time_match {
+            TimeMatch::Contained => offer.endDate <= params.time_range_end,
+            TimeMatch::Overlap => offer.endDate >= params.time_range_start,
+        };
+        in_range
+            && params.days.matches(offer.endDate - offer.startDate)
+            && params.data_prefix.as_deref().is_none_or(|prefix| offer.data.starts_with(prefix))
+    };
+    let windows = scan_windows(
+        cache,
+        regions,
+        params.region,
+        params.time_range_start,
+        params.time_range_end,
+        params.days,
+        params.time_match,
+    );
+    let mut offers = Vec::new();
+
+    // The offer has to match the requested range, last the requested number of days and,
+    // for dataPrefix, have data starting with those bytes.
+    // Both paths keep the cache order, so results don't depend on the feature
+    for window in windows {
+        #[cfg(feature = "parallel")]
+        offers.par_extend(window.par_iter().filter(fits).map(FilteredOffer::from));
+        #[cfg(not(feature = "parallel"))]
+        offers.extend(window.iter().filter(fits).map(FilteredOffer::from));
+    }
+
+    offers
+}
+
+// Offers a search has to look at, one slice per searched region
+fn scan_windows<'a>(
+    cache: &'a OfferCache,
+    regions: &RegionTree,
+    region: RegionScope,
+    time_range_start: i64,
+    time_range_end: i64,
+    days: DaysFilter,
+    time_match: TimeMatch,
+) -> Vec<&'a [Offer]> {
+    // Bounds on the startDate of a match
+    let (earliest_start, latest_start) = match time_match {
+        // Starting inside the range, early enough to last the shortest duration before its end
+        TimeMatch::Contained => (time_range_start, time_range_end.saturating_sub(days.min_duration())),
+        // Starting before the range ends, late enough to reach into it with the longest duration
+        TimeMatch::Overlap => (time_range_start.saturating_sub(days.max_duration()), time_range_end),
+    };
+
+    region
+        .ids(cache, regions)
+        .into_iter()
+        .map(|id| {
+            // Region offers are sorted by startDate, so the candidates are a contiguous slice
+            let region_offers = cache.region(id);
+            let first = region_offers.partition_point(|offer| offer.startDate < earliest_start);
+            let last = region_offers.partition_point(|offer| offer.startDate <= latest_start);
+            &region_offers[first..last.max(first)]
+        })
+        .collect()
+}
+
+// Predicates of the Filters, one per dimension
+fn matches_seats(offer: &FilteredOffer, min_number_seats: Option<u8>, number_seats: Option<u8>) -> bool {
+    min_number_seats.is_none_or(|min_seats| offer.numberSeats >= min_seats)
+        && number_seats.is_none_or(|seats| offer.numberSeats == seats)
+}
+
+// Prices match the half-open interval [minPrice, maxPrice)
+fn matches_price(offer: &FilteredOffer, min_price: Option<u16>, max_price: Option<u16>) -> bool {
+    min_price.is_none_or(|min_p| price_at_least(offer, min_p))
+        && max_price.is_none_or(|max_p| price_below(offer, max_p))
+}
+
+// minPrice is inclusive
+fn price_at_least(offer: &FilteredOffer, min_price: u16) -> bool {
+    offer.price >= min_price
+}
+
+// maxPrice is exclusive
+fn price_below(offer: &FilteredOffer, max_price: u16) -> bool {
+    offer.price < max_price
+}
+
+fn matches_car_type(offer: &FilteredOffer, car_type: Option<CarTypeSet>) -> bool {
+    car_type.is_none_or(|car_types| car_types.contains(offer.carType))
+}
+
+// The filter only ever restricts: true keeps vollkasko offers, false is the same as omitting it
+fn matches_vollkasko(offer: &FilteredOffer, only_vollkasko: Option<bool>) -> bool {
+    !only_vollkasko.unwrap_or(false) || offer.hasVollkasko
+}
+
+// Both bounds are inclusive, a minimum above the maximum simply matches nothing
+fn matches_free_kilometer(
+    offer: &FilteredOffer,
+    min_free_kilometer: Option<u16>,
+    max_free_kilometer: Option<u16>,
+) -> bool {
+    min_free_kilometer.is_none_or(|min_fk| offer.freeKilometers >= min_fk)
+        && max_free_kilometer.is_none_or(|max_fk| offer.freeKilometers <= max_fk)
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// The attribute an order sorts by, the direction is up to compare_sort_keys
+fn sort_key(offer: &FilteredOffer, sort_order: SortOrder) -> i64 {
+    match sort_order {
+        SortOrder::PriceAsc | SortOrder::PriceDesc => offer.price as i64,
+        SortOrder::KilometersAsc | SortOrder::KilometersDesc => offer.freeKilometers as i64,
+        SortOrder::StartDateAsc | SortOrder::StartDateDesc => offer.startDate,
+        SortOrder::CreatedDesc => offer.createdAt,
+    }
+}
+
+// Ties are broken by ID, IDs are unique so this is a total order and every page request
+// sees the same sequence; that also makes the cheaper unstable sort safe.
+fn compare_sort_keys(sort_order: SortOrder, a: (i64, Uuid), b: (i64, Uuid)) -> std::cmp::Ordering {
+    let order = match sort_order {
+        SortOrder::PriceAsc | SortOrder::KilometersAsc | SortOrder::StartDateAsc => a.0.cmp(&b.0),
+        SortOrder::PriceDesc | SortOrder::KilometersDesc | SortOrder::StartDateDesc | SortOrder::CreatedDesc => {
+            b.0.cmp(&a.0)
+        }
+    };
+    order.then_with(|| a.1.cmp(&b.1))
+}
+
+// Only the first `limit` offers end up sorted, the rest are left in an unspecified order.
+// Selecting them first makes a request linear in the matches plus the sort of its pages.
+fn sort_offers(offers: &mut [FilteredOffer], sort_order: SortOrder, limit: usize) {
+    let compare = |a: &FilteredOffer, b: &FilteredOffer| {
+        compare_sort_keys(sort_order, (sort_key(a, sort_order), a.ID), (sort_key(b, sort_order), b.ID))
+    };
+
+    if limit == 0 {
+        return;
+    }
+    if limit < offers.len() {
+        offers.select_nth_unstable_by(limit - 1, compare);
+    }
+    let limit = limit.min(offers.len());
+    offers[..limit].sort_unstable_by(compare);
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/offers",
+    params(("regionID" = Option<i32>, Query, description = "Only delete the offers of this region")),
+    responses(
+        (status = 200, description = "Data was cleaned up, or the number of deleted offers of the region"),
+        (status = 400, description = "Invalid regionID", body = ErrorBody),
+        (status = 500, description = "Cleanup failed", body = ErrorBody),
+    )
+)]
+pub async fn cleanup_data(
+    State(db): State<Database>,
+    State(shards): State<Shards>,
+    State(write_retry): State<WriteRetry>,
+    State(cache): State<SharedCache>,
+    State(write_lock): State<WriteLock>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    // With a regionID only that region is purged
+    let region_id: Option<i32> = match params.get("regionID").map(|val| val.parse()) {
+        Some(Ok(region_id)) => Some(region_id),
+        Some(Err(_)) => return ApiError::bad_request("Invalid regionID").with_field("regionID").into_response(),
+        None => None,
+    };
+
+    run_write(&write_lock, move || {
+        if let Some(region_id) = region_id {
+            let deleted = match delete_region(&db, shards, write_retry, region_id) {
+                Ok(deleted) => deleted,
+                Err(e) => {
+                    tracing::error!("Failed to delete region {}: {}", region_id, e);
+                    return ApiError::internal("Failed to delete offers").into_response();
+                }
+            };
+            cache.write().unwrap().remove_many(&deleted);
+            return Json(serde_json::json!({ "deleted": deleted.len() })).into_response();
+        }
+
+        // Clear the offers, their indexes and the cache
+        if let Err(e) = clear_all(&db) {
+            tracing::error!("Failed to clean up data: {}", e);
+            return ApiError::internal("Failed to clean up data").into_response();
+        }
+        cache.write().unwrap().clear();
+
+        (StatusCode::OK, "Data was cleaned up").into_response()
+    })
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/offers/export",
+    responses(
+        (status = 200, description = "Every offer as a binary snapshot", content_type = "application/octet-stream"),
+    )
+)]
+pub async fn export_offers(State(cache): State<SharedCache>) -> impl IntoResponse {
+    // A copy keeps the snapshot consistent without holding the read lock, and so blocking
+    // writers, while a slow client downloads it
+    let offers: Vec<Offer> = cache.read().unwrap().offers().cloned().collect();
+
+    // Snapshots are plain concatenations of records, so every chunk is encoded on its own
+    // as hyper asks for more
+    let chunk_count = offers.len().div_ceil(STREAM_CHUNK_SIZE);
+    let records = stream::iter(0..chunk_count).map(move |chunk| {
+        let end = ((chunk + 1) * STREAM_CHUNK_SIZE).min(offers.len());
+        encode_snapshot(&offers[chunk * STREAM_CHUNK_SIZE..end])
+    });
+
+    ([(header::CONTENT_TYPE, "application/octet-stream")], StreamBody::new(records))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/offers/import",
+    params(("nowal" = Option<bool>, Query, description = "Skip the write-ahead log and flush afterwards, a crash during the request loses the offers")),
+    request_body(content = Vec<u8>, description = "Snapshot produced by GET /api/offers/export", content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Existing data was replaced by the snapshot"),
+        (status = 400, description = "Invalid snapshot", body = ErrorBody),
+        (status = 413, description = "Body exceeds the configured limit", body = ErrorBody),
+        (status = 500, description = "Import failed", body = ErrorBody),
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn import_offers(
+    State(db): State<Database>,
+    State(shards): State<Shards>,
+    State(write_retry): State<WriteRetry>,
+    State(compression): State<DataCompression>,
+    State(cache): State<SharedCache>,
+    State(write_lock): State<WriteLock>,
+    Query(params): Query<HashMap<String, String>>,
+    snapshot: Bytes,
+) -> impl IntoResponse {
+    let mut offers = match decode_snapshot(&snapshot) {
+        Ok(offers) => offers,
+        Err(e) => return ApiError::bad_request(e).into_response(),
+    };
+    if let Err(e) = offers.iter().try_for_each(validate) {
+        return e.into_response();
+    }
+
+    // The snapshot replaces everything, like a cleanup followed by an upload
+    let wal = wal_param(&params);
+    run_write(&write_lock, move || {
+        if let Err(e) = clear_all(&db) {
+            tracing::error!("Failed to clean up data before import: {}", e);
+            return ApiError::internal("Failed to clean up data").into_response();
+        }
+        if let Err(e) = write_offers(&db, shards, write_retry, wal, compression, &mut offers) {
+            tracing::error!("Failed to import offers: {}", e);
+            // The old data is gone already
+            cache.write().unwrap().clear();
+            return ApiError::internal("Failed to import offers").into_response();
+        }
+        let imported = offers.len();
+        {
+            let mut cache = cache.write().unwrap();
+            cache.clear();
+            cache.insert(offers);
+        }
+        if let Err(e) = flush_unlogged(&db, wal) {
+            return e.into_response();
+        }
+
+        Json(serde_json::json!({ "imported": imported })).into_response()
+    })
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/offers/ndjson",
+    request_body(content = String, description = "One offer JSON object per line", content_type = "application/x-ndjson"),
+    responses(
+        (status = 200, description = "Number of inserted offers and the rejected lines", body = NdjsonReport),
+        (status = 400, description = "The body couldn't be read", body = ErrorBody),
+        (status = 500, description = "Insert failed, earlier batches stay inserted", body = ErrorBody),
+    )
+)]
+pub async fn ingest_ndjson(
+    State(db): State<Database>,
+    State(shards): State<Shards>,
+    State(write_retry): State<WriteRetry>,
+    State(compression): State<DataCompression>,
+    State(cache): State<SharedCache>,
+    State(write_lock): State<WriteLock>,
+    RawBody(mut body): RawBody,
+) -> impl IntoResponse {
+    let mut report = NdjsonReport {
+        inserted: 0,
+        errors: Vec::new(),
+        omittedErrors: 0,
+    };
+    // Bytes of the current incomplete line and the offers parsed since the last insert
+    let mut pending: Vec<u8> = Vec::new();
+    let mut offers: Vec<Offer> = Vec::with_capacity(NDJSON_BATCH_SIZE);
+    let mut line_number = 0;
+    // Leading bytes of pending already searched for a newline, so each byte is searched once
+    let mut scanned = 0;
+    // The current line went over MAX_NDJSON_LINE_LEN, its bytes are dropped until it ends
+    let mut oversized = false;
+
+    // The body is read chunk by chunk, memory stays bounded by the batch size, MAX_NDJSON_LINE_LEN
+    // and MAX_NDJSON_ERRORS
+    loop {
+        let chunk = match body.data().await {
+            Some(Ok(chunk)) => Some(chunk),
+            Some(Err(e)) => return ApiError::bad_request(format!("Failed to read body: {}", e)).into_response(),
+            None => None,
+        };
+        let done = chunk.is_none();
+        if let Some(chunk) = chunk {
+            pending.extend_from_slice(&chunk);
+        }
+
+        // Every complete line, and at the end a last line without a newline
+        let mut consumed = 0;
+        let mut complete_line = |line: &[u8], oversized: &mut bool| {
+            line_number += 1;
+            let too_long = std::mem::take(oversized) || line.len() > MAX_NDJSON_LINE_LEN;
+            if too_long {
+                let error = format!("Line is longer than {} bytes", MAX_NDJSON_LINE_LEN);
+                report_ndjson_error(&mut report, line_number, error);
+            } else {
+                parse_ndjson_line(line, line_number, &mut offers, &mut report);
+            }
+        };
+        while let Some(len) = pending[scanned..].iter().position(|&byte| byte == b'\n') {
+            let end = scanned + len;
+            complete_line(&pending[consumed..end], &mut oversized);
+            consumed = end + 1;
+            scanned = consumed;
+        }
+        if done && (consumed < pending.len() || oversized) {
+            complete_line(&pending[consumed..], &mut oversized);
+            consumed = pending.len();
+        }
+        pending.drain(..consumed);
+        scanned = pending.len();
+        if pending.len() > MAX_NDJSON_LINE_LEN {
+            oversized = true;
+            pending.clear();
+            scanned = 0;
+        }
+
+        if offers.len() >= NDJSON_BATCH_SIZE || (done && !offers.is_empty()) {
+            let mut batch = std::mem::replace(&mut offers, Vec::with_capacity(NDJSON_BATCH_SIZE));
+            let (db, cache) = (db.clone(), cache.clone());
+            let inserted = run_write(&write_lock, move || {
+                if let Err(e) = insert_offers(&db, shards, write_retry, Wal::Enabled, compression, &mut batch) {
+                    tracing::error!("Failed to insert {} offers: {}", batch.len(), e);
+                    return None;
+                }
+                let inserted = batch.len() as u32;
+                cache.write().unwrap().insert(batch);
+                Some(inserted)
+            });
+            match inserted.await {
+                Some(inserted) => report.inserted += inserted,
+                None => {
+                    let message = format!("Failed to insert offers after inserting {}", report.inserted);
+                    return ApiError::internal(message).into_response();
+                }
+            }
+        }
+
+        if done {
+            break;
+        }
+    }
+
+    Json(report).into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/offers/seed",
+    params(
+        ("count" = Option<u32>, Query, description = "Number of offers to generate, 1000 by default"),
+        ("regions" = Option<u32>, Query, description = "Offers go to regions 0..regions, 10 by default"),
+        ("seed" = Option<u64>, Query, description = "RNG seed, the same seed generates the same offers"),
+    ),
+    responses(
+        (status = 200, description = "Number of generated offers and the seed used"),
+        (status = 400, description = "Invalid parameter", body = ErrorBody),
+        (status = 500, description = "Insert failed", body = ErrorBody),
+    )
+)]
+pub async fn seed_offers(
+    State(db): State<Database>,
+    State(shards): State<Shards>,
+    State(write_retry): State<WriteRetry>,
+    State(compression): State<DataCompression>,
+    State(cache): State<SharedCache>,
+    State(write_lock): State<WriteLock>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let (count, regions, seed) = match (
+        param_or(&params, "count", 1000u64),
+        param_or(&params, "regions", 10u64),
+        param_or(&params, "seed", 0u64),
+    ) {
+        (Ok(count), Ok(regions), Ok(seed)) => (count, regions, seed),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return e.into_response(),
+    };
+    if count == 0 || count > MAX_SEED_COUNT {
+        let message = format!("count must be between 1 and {}", MAX_SEED_COUNT);
+        return ApiError::bad_request(message).with_field("count").into_response();
+    }
+    if regions == 0 || regions > i32::MAX as u64 {
+        return ApiError::bad_request("regions must be a positive region count").with_field("regions").into_response();
+    }
+
+    // Generated and written in batches like an NDJSON upload
+    let mut rng = Rng::new(seed);
+    let mut remaining = count as usize;
+    while remaining > 0 {
+        let mut batch: Vec<Offer> = (0..remaining.min(NDJSON_BATCH_SIZE))
+            .map(|_| random_offer(&mut rng, regions as u32))
+            .collect();
+        remaining -= batch.len();
+
+        let (db, cache) = (db.clone(), cache.clone());
+        let inserted = run_write(&write_lock, move || {
+            if let Err(e) = insert_offers(&db, shards, write_retry, Wal::Enabled, compression, &mut batch) {
+                tracing::error!("Failed to insert {} seeded offers: {}", batch.len(), e);
+                return false;
+            }
+            cache.write().unwrap().insert(batch);
+            true
+        });
+        if !inserted.await {
+            return ApiError::internal("Failed to insert offers").into_response();
+        }
+    }
+
+    Json(serde_json::json!({ "inserted": count, "seed": seed })).into_response()
+}
+
+// Optional query parameter, a present but unparseable value is a bad request
+fn param_or<T: FromStr>(params: &HashMap<String, String>, name: &str, default: T) -> Result<T, ApiError> {
+    Ok(optional_param(params, name)?.unwrap_or(default))
+}
+
+// An optional parameter that is rejected, instead of ignored, when it doesn't parse
+fn optional_param<T: FromStr>(params: &HashMap<String, String>, name: &str) -> Result<Option<T>, ApiError> {
+    params
+        .get(name)
+        .map(|val| {
+            val.parse()
+                .map_err(|_| ApiError::bad_request(format!("Invalid {}", name)).with_field(name))
+        })
+        .transpose()
+}
+
+// ?nowal=true trades durability for bulk load speed, see Wal::Disabled
+fn wal_param(params: &HashMap<String, String>) -> Wal {
+    match params.get("nowal").and_then(|v| v.parse().ok()) {
+        Some(true) => Wal::Disabled,
+        _ => Wal::Enabled,
+    }
+}
+
+// Flushes after a write without the log, see Wal::Disabled. The write succeeded already and
+// is visible, so a failed flush is reported on its own.
+fn flush_unlogged(db: &Database, wal: Wal) -> Result<(), ApiError> {
+    if wal == Wal::Enabled {
+        return Ok(());
+    }
+    flush_all(db).map_err(|e| {
+        tracing::error!("Failed to flush offers written without the log: {}", e);
+        ApiError::internal("Offers were written but not flushed, a crash loses them")
+    })
+}
+
+// Parses and validates one NDJSON line, blank lines are skipped
+fn parse_ndjson_line(line: &[u8], line_number: u32, offers: &mut Vec<Offer>, report: &mut NdjsonReport) {
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return;
+    }
+    let result = serde_json::from_slice::<Offer>(line)
+        .map_err(|e| e.to_string())
+        .and_then(|offer| validate(&offer).map(|()| offer).map_err(|e| e.body.error));
+    match result {
+        Ok(offer) => offers.push(offer),
+        Err(error) => report_ndjson_error(report, line_number, error),
+    }
+}
+
+fn report_ndjson_error(report: &mut NdjsonReport, line_number: u32, error: String) {
+    if report.errors.len() >= MAX_NDJSON_ERRORS {
+        report.omittedErrors += 1;
+        return;
+    }
+    report.errors.push(NdjsonLineError {
+        line: line_number,
+        error,
+    });
+}
+
+// Upserts offers in one atomic batch, replacing the index entries of stored versions.
+// Offers without a createdAt get the one of their stored version, or the current time.
+// Of several offers with the same ID only the last one is kept and written.
+fn insert_offers(
+    db: &Database,
+    shards: Shards,
+    retry: WriteRetry,
+    wal: Wal,
+    compression: DataCompression,
+    offers: &mut Vec<Offer>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    dedup_ids(offers);
+    let handles = shards.handles(db)?;
+    let mut batch = WriteBatch::default();
+
+    // A stored version sits in another shard when its region changed, so check them all
+    let lookups: Vec<(usize, Uuid)> = offers
+        .iter()
+        .flat_map(|offer| (0..handles.len()).map(move |shard| (shard, offer.ID)))
+        .collect();
+    let existing = db.multi_get_cf(lookups.iter().map(|(shard, id)| (&handles[*shard], offer_key(id))));
+    let mut created: HashMap<Uuid, i64> = HashMap::new();
+    for ((shard, id), value) in lookups.iter().zip(existing) {
+        if let Some(value) = value? {
+            if let Ok(previous) = decode_offer(&value) {
+                delete_index_entries(db, &mut batch, &previous)?;
+                if let Some(created_at) = previous.createdAt {
+                    created.insert(*id, created_at);
+                }
+            }
+            // Overwritten below when the offer stays in this shard
+            batch.delete_cf(&handles[*shard], offer_key(id));
+        }
+    }
+
+    let now = now_ms();
+    for offer in offers.iter_mut() {
+        offer.createdAt = offer.createdAt.or_else(|| created.get(&offer.ID).copied()).or(Some(now));
+    }
+    for offer in offers.iter() {
+        let shard = &handles[shards.of_region(offer.mostSpecificRegionID)];
+        batch.put_cf(shard, offer_key(&offer.ID), encode_offer(offer, compression)?);
+        put_index_entries(db, &mut batch, offer)?;
+    }
+    write_with_retry_wal(db, batch, retry, wal)?;
+    Ok(())
+}
+
+// Writes offers and their index entries without looking for stored versions,
+// only valid when the database is known to hold none of them.
+// Like insert_offers only the last offer of an ID is kept.
+fn write_offers(
+    db: &Database,
+    shards: Shards,
+    retry: WriteRetry,
+    wal: Wal,
+    compression: DataCompression,
+    offers: &mut Vec<Offer>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    dedup_ids(offers);
+    let handles = shards.handles(db)?;
+    let mut batch = WriteBatch::default();
+    for offer in offers.iter() {
+        let shard = &handles[shards.of_region(offer.mostSpecificRegionID)];
+        batch.put_cf(shard, offer_key(&offer.ID), encode_offer(offer, compression)?);
+        put_index_entries(db, &mut batch, offer)?;
+    }
+    write_with_retry_wal(db, batch, retry, wal)?;
+    Ok(())
+}
+
+fn insert_offer(
+    db: &Database,
+    shards: Shards,
+    retry: WriteRetry,
+    compression: DataCompression,
+    offer: Offer,
+) -> Result<Offer, Box<dyn std::error::Error>> {
+    let mut offers = vec![offer];
+    insert_offers(db, shards, retry, Wal::Enabled, compression, &mut offers)?;
+    Ok(offers.remove(0))
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+// A raw stored offer and the shard holding it
+type ShardValue = (usize, Vec<u8>);
+
+// Offers are keyed by ID alone, so a lookup by ID checks every shard
+fn read_offer_value(db: &Database, shards: Shards, id: &Uuid) -> Result<Option<ShardValue>, Box<dyn std::error::Error>> {
+    let handles = shards.handles(db)?;
+    for (shard, value) in db.multi_get_cf(handles.iter().map(|cf| (cf, offer_key(id)))).into_iter().enumerate() {
+        if let Some(value) = value? {
+            return Ok(Some((shard, value)));
+        }
+    }
+    Ok(None)
+}
+
+// Fetches several offers with one multi-get across every shard, missing IDs are left out
+fn read_offers(
+    db: &Database,
+    shards: Shards,
+    ids: &[Uuid],
+) -> Result<BTreeMap<Uuid, Offer>, Box<dyn std::error::Error>> {
+    let handles = shards.handles(db)?;
+    let lookups: Vec<(usize, &Uuid)> = ids
+        .iter()
+        .flat_map(|id| (0..handles.len()).map(move |shard| (shard, id)))
+        .collect();
+    let values = db.multi_get_cf(lookups.iter().map(|(shard, id)| (&handles[*shard], offer_key(id))));
+
+    let mut offers = BTreeMap::new();
+    for ((_, id), value) in lookups.iter().zip(values) {
+        if let Some(value) = value? {
+            offers.insert(**id, decode_offer(&value)?);
+        }
+    }
+    Ok(offers)
+}
+
+fn read_offer(db: &Database, shards: Shards, id: &Uuid) -> Result<Option<Offer>, Box<dyn std::error::Error>> {
+    match read_offer_value(db, shards, id)? {
+        Some((_, value)) => Ok(Some(decode_offer(&value)?)),
+        None => Ok(None),
+    }
+}
+
+fn delete_offer_by_id(
+    db: &Database,
+    shards: Shards,
+    retry: WriteRetry,
+    id: &Uuid,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (shard, value) = match read_offer_value(db, shards, id)? {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+    let mut batch = WriteBatch::default();
+    // The stored offer tells us which index entries to remove
+    if let Ok(offer) = decode_offer(&value) {
+        delete_index_entries(db, &mut batch, &offer)?;
+    }
+    batch.delete_cf(&shards.handle(db, shard)?, offer_key(id));
+    write_with_retry(db, batch, retry)?;
+    Ok(())
+}
+
+// Deletes every offer of a region found through the region index, returns their IDs
+fn delete_region(
+    db: &Database,
+    shards: Shards,
+    retry: WriteRetry,
+    region_id: i32,
+) -> Result<Vec<Uuid>, Box<dyn std::error::Error>> {
+    let region_index = db.cf_handle(CF_REGION_INDEX).ok_or("Missing region index")?;
+    let prefix = region_prefix(region_id);
+    let mut ids = Vec::new();
+    for item in db.iterator_cf(&region_index, IteratorMode::From(&prefix, Direction::Forward)) {
+        let (index_key, _) = item?;
+        if !index_key.starts_with(&prefix) {
+            break;
+        }
+        ids.push(Uuid::from_slice(&index_key[prefix.len()..])?);
+    }
+
+    // All offers of a region live in the same shard
+    let shard = shards.of_region_handle(db, region_id)?;
+    let mut batch = WriteBatch::default();
+    for (id, value) in ids.iter().zip(db.multi_get_cf(ids.iter().map(|id| (&shard, offer_key(id))))) {
+        match value?.map(|value| decode_offer(&value)) {
+            Some(Ok(offer)) => delete_index_entries(db, &mut batch, &offer)?,
+            // Without a readable offer only the region entry is known
+            _ => batch.delete_cf(&region_index, region_index_key(region_id, id)),
+        }
+        batch.delete_cf(&shard, offer_key(id));
+    }
+    write_with_retry(db, batch, retry)?;
+    Ok(ids)
+}
+
+// Aggregation functions
+fn compute_price_ranges(offers: &[FilteredOffer], price_buckets: &PriceBuckets) -> Vec<PriceRange> {
+    let width = match price_buckets {
+        PriceBuckets::Width(width) => *width,
+        PriceBuckets::Fixed(boundaries) => return compute_fixed_price_ranges(offers, boundaries),
+    };
+    // A zero width is rejected by the handler, don't divide by it anyway
+    if width == 0 {
+        return Vec::new();
+    }
+
+    // Count offers per bucket, BTreeMap keeps the buckets sorted by start
+    let mut buckets: BTreeMap<u32, u32> = BTreeMap::new();
+    for offer in offers {
+        let bucket = offer.price as u32 / width;
+        *buckets.entry(bucket).or_insert(0) += 1;
+    }
+
+    buckets
+        .into_iter()
+        .map(|(bucket, count)| PriceRange {
+            start: bucket * width,
+            end: bucket * width + width,
+            count,
+        })
+        .collect()
+}
+
+// Offers outside the outermost boundaries aren't counted in any band
+fn compute_fixed_price_ranges(offers: &[FilteredOffer], boundaries: &[u32]) -> Vec<PriceRange> {
+    let mut counts = vec![0u32; boundaries.len().saturating_sub(1)];
+    for offer in offers {
+        // Number of boundaries at or below the price, the band is the one starting at the last of them
+        let above = boundaries.partition_point(|&boundary| boundary <= offer.price as u32);
+        if above > 0 && above < boundaries.len() {
+            counts[above - 1] += 1;
+        }
+    }
+
+    boundaries
+        .windows(2)
+        .zip(counts)
+        .map(|(band, count)| PriceRange {
+            start: band[0],
+            end: band[1],
+            count,
+        })
+        .collect()
+}
+
+fn compute_price_stats(offers: &[FilteredOffer]) -> PriceStats {
+    let mut prices: Vec<u16> = offers.iter().map(|offer| offer.price).collect();
+    if prices.is_empty() {
+        return PriceStats::default();
+    }
+
+    // The upper middle price, and for an even count the lower one is the largest price before it
+    let middle = prices.len() / 2;
+    let (lower, upper, _) = prices.select_nth_unstable(middle);
+    let upper = *upper;
+    let median = match lower.iter().max() {
+        Some(&lower) if offers.len().is_multiple_of(2) => (lower as f64 + upper as f64) / 2.0,
+        _ => upper as f64,
+    };
+
+    PriceStats {
+        minPrice: offers.iter().map(|offer| offer.price).min(),
+        maxPrice: offers.iter().map(|offer| offer.price).max(),
+        medianPrice: Some(median),
+    }
+}
+
+fn compute_car_type_counts(offers: &[FilteredOffer]) -> CarTypeCount {
+    let mut counts = CarTypeCount {
+        small: 0,
+        sports: 0,
+        luxury: 0,
+        family: 0,
+    };
+    for offer in offers {
+        match offer.carType {
+            CarType::Small => counts.small += 1,
+            CarType::Sports => counts.sports += 1,
+            CarType::Luxury => counts.luxury += 1,
+            CarType::Family => counts.family += 1,
+        }
+    }
+    counts
+}
+
+// One entry per seat count present, fewest seats first
+fn compute_seats_count(offers: &[FilteredOffer]) -> Vec<SeatsCount> {
+    let mut counts: BTreeMap<u8, u32> = BTreeMap::new();
+    for offer in offers {
+        *counts.entry(offer.numberSeats).or_insert(0) += 1;
+    }
+
+    counts
+        .into_iter()
+        .map(|(number_seats, count)| SeatsCount {
+            numberSeats: number_seats,
+            count,
+        })
+        .collect()
+}
+
+fn compute_free_kilometer_ranges(offers: &[FilteredOffer], width: u32) -> Vec<FreeKilometerRange> {
+    if width == 0 {
+        return Vec::new();
+    }
+
+    // Same bucketing as the price ranges, keyed by free kilometers
+    let mut buckets: BTreeMap<u32, u32> = BTreeMap::new();
+    for offer in offers {
+        let bucket = offer.freeKilometers as u32 / width;
+        *buckets.entry(bucket).or_insert(0) += 1;
+    }
+
+    buckets
+        .into_iter()
+        .map(|(bucket, count)| FreeKilometerRange {
+            start: bucket * width,
+            end: bucket * width + width,
+            count,
+        })
+        .collect()
+}
+
+fn compute_free_kilometer_stats(offers: &[FilteredOffer]) -> FreeKilometerStats {
+    FreeKilometerStats {
+        minFreeKilometer: offers.iter().map(|offer| offer.freeKilometers).min(),
+        maxFreeKilometer: offers.iter().map(|offer| offer.freeKilometers).max(),
+    }
+}
+
+// Most offers first, ties ordered by region ID
+fn compute_region_counts(offers: &[FilteredOffer]) -> Vec<RegionCount> {
+    let mut counts: HashMap<i32, u32> = HashMap::new();
+    for offer in offers {
+        *counts.entry(offer.mostSpecificRegionID).or_insert(0) += 1;
+    }
+
+    let mut region_counts: Vec<RegionCount> = counts
+        .into_iter()
+        .map(|(region_id, count)| RegionCount {
+            regionID: region_id,
+            count,
+        })
+        .collect();
+    region_counts.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.regionID.cmp(&b.regionID)));
+    region_counts
+}
+
+fn compute_vollkasko_count(offers: &[FilteredOffer]) -> VollkaskoCount {
+    let true_count = offers.iter().filter(|offer| offer.hasVollkasko).count() as u32;
+    VollkaskoCount {
+        trueCount: true_count,
+        falseCount: offers.len() as u32 - true_count,
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{init_db, DbConfig};
+    use crate::regions::Region;
+    use crate::state::{AppState, ADMIN_TOKEN_HEADER};
+    use crate::testutil::{body_bytes, body_json, json_request, offer, request, send, test_app, test_config, test_state, TempDir, DAY};
+    use axum::body::Body;
+    use axum::http::Request;
+    use axum::routing::post;
+    use axum::Router;
+
+    fn offers_router(state: AppState) -> Router {
+        Router::new().route("/api/offers", post(create_offers)).with_state(state)
+    }
+
+    #[test]
+    fn unlogged_import_survives_a_reopen() {
+        let dir = TempDir::new();
+        let config = DbConfig {
+            shards: 2,
+            ..DbConfig::default()
+        };
+        let shards = Shards::new(config.shards);
+        let db = init_db(dir.path(), &config).unwrap();
+        let mut offers = vec![
+            offer(),
+            Offer {
+                mostSpecificRegionID: 2,
+                ..offer()
+            },
+        ];
+        write_offers(&db, shards, config.write_retry(), Wal::Disabled, DataCompression::None, &mut offers).unwrap();
+        flush_unlogged(&db, Wal::Disabled).unwrap();
+        drop(db);
+
+        let db = init_db(dir.path(), &config).unwrap();
+        assert_eq!(OfferCache::load(&db, shards).unwrap().len(), 2);
+        for offer in &offers {
+            assert_eq!(read_offer(&db, shards, &offer.ID).unwrap().unwrap().ID, offer.ID);
+        }
+    }
+
+    #[test]
+    fn required_params_reports_missing_and_invalid_together() {
+        let params: HashMap<String, String> = [("pageSize", "many"), ("page", "1")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let mut required = RequiredParams::new(&params);
+        let _: u32 = required.get("sortOrder");
+        let _: u32 = required.get("pageSize");
+        let _: u32 = required.get("page");
+
+        let error = required.finish().unwrap_err();
+        assert_eq!(error.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error.body.error, "Missing sortOrder; invalid pageSize");
+        assert_eq!(error.body.fields, ["sortOrder", "pageSize"]);
+    }
+
+    #[tokio::test]
+    async fn dry_run_reports_duplicates_and_writes_nothing() {
+        let dir = TempDir::new();
+        let state = test_state(&dir);
+        let router = offers_router(state.clone());
+        let first = offer();
+        let repeat = Offer {
+            price: 200,
+            ..first.clone()
+        };
+        let invalid = Offer { price: 0, ..offer() };
+        let offers = serde_json::to_value([&first, &repeat, &invalid]).unwrap();
+
+        let response = send(&router, json_request("POST", "/api/offers?validate=true", &offers)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let report = body_json(response).await;
+        assert_eq!(report["valid"], 1);
+        let errors = report["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0]["ID"], first.ID.to_string());
+        assert_eq!(errors[0]["field"], "ID");
+        assert_eq!(errors[1]["field"], "price");
+
+        assert_eq!(state.cache.read().unwrap().len(), 0);
+        assert!(read_offer(&state.db, state.shards, &first.ID).unwrap().is_none());
+    }
+
+    fn offers_priced(prices: &[u16]) -> Vec<Offer> {
+        prices.iter().map(|&price| Offer { price, ..offer() }).collect()
+    }
+
+    fn filtered(offers: &[Offer]) -> Vec<FilteredOffer<'_>> {
+        offers.iter().map(FilteredOffer::from).collect()
+    }
+
+    fn price_ranges(offers: &[Offer], price_buckets: PriceBuckets) -> Vec<(u32, u32, u32)> {
+        compute_price_ranges(&filtered(offers), &price_buckets)
+            .into_iter()
+            .map(|range| (range.start, range.end, range.count))
+            .collect()
+    }
+
+    #[test]
+    fn price_ranges_bucket_by_width() {
+        let offers = offers_priced(&[1, 9, 10, 19, 35]);
+        assert_eq!(
+            price_ranges(&offers, PriceBuckets::Width(10)),
+            [(0, 10, 2), (10, 20, 2), (30, 40, 1)]
+        );
+    }
+
+    #[test]
+    fn price_ranges_with_a_width_not_dividing_the_prices() {
+        let offers = offers_priced(&[6, 7, 13, 14, 100]);
+        assert_eq!(
+            price_ranges(&offers, PriceBuckets::Width(7)),
+            [(0, 7, 1), (7, 14, 2), (14, 21, 1), (98, 105, 1)]
+        );
+    }
+
+    #[test]
+    fn car_type_counts_count_every_type() {
+        let offers: Vec<Offer> = [CarType::Small, CarType::Family, CarType::Luxury, CarType::Family]
+            .into_iter()
+            .map(|car_type| Offer {
+                carType: car_type,
+                ..offer()
+            })
+            .collect();
+        let counts = compute_car_type_counts(&filtered(&offers));
+        assert_eq!((counts.small, counts.sports, counts.luxury, counts.family), (1, 0, 1, 2));
+    }
+
+    #[test]
+    fn free_kilometer_ranges_start_a_bucket_at_each_multiple() {
+        let offers: Vec<Offer> = [0, 99, 100, 199, 200]
+            .into_iter()
+            .map(|free_kilometers| Offer {
+                freeKilometers: free_kilometers,
+                ..offer()
+            })
+            .collect();
+        let ranges: Vec<(u32, u32, u32)> = compute_free_kilometer_ranges(&filtered(&offers), 100)
+            .into_iter()
+            .map(|range| (range.start, range.end, range.count))
+            .collect();
+        assert_eq!(ranges, [(0, 100, 2), (100, 200, 2), (200, 300, 1)]);
+    }
+
+    #[test]
+    fn vollkasko_count_splits_the_offers() {
+        let offers: Vec<Offer> = [true, false, true, true]
+            .into_iter()
+            .map(|has_vollkasko| Offer {
+                hasVollkasko: has_vollkasko,
+                ..offer()
+            })
+            .collect();
+        let count = compute_vollkasko_count(&filtered(&offers));
+        assert_eq!((count.trueCount, count.falseCount), (3, 1));
+    }
+
+    // Sorted IDs, offers built from them in order sort by ID
+    fn sorted_ids(count: usize) -> Vec<Uuid> {
+        let mut ids: Vec<Uuid> = (0..count).map(|_| Uuid::new_v4()).collect();
+        ids.sort();
+        ids
+    }
+
+    fn sorted_prices(offers: &mut [FilteredOffer], sort_order: SortOrder) -> Vec<u16> {
+        sort_offers(offers, sort_order, offers.len());
+        offers.iter().map(|offer| offer.price).collect()
+    }
+
+    #[test]
+    fn sorts_by_price_not_by_id() {
+        let offers: Vec<Offer> = sorted_ids(4)
+            .into_iter()
+            .zip([30, 10, 40, 20])
+            .map(|(id, price)| Offer { ID: id, price, ..offer() })
+            .collect();
+        let mut filtered = filtered(&offers);
+        assert_eq!(sorted_prices(&mut filtered, SortOrder::PriceAsc), [10, 20, 30, 40]);
+        assert_eq!(sorted_prices(&mut filtered, SortOrder::PriceDesc), [40, 30, 20, 10]);
+    }
+
+    // Query pairs of a search in region 1 over days 0 to 100 for offers of 2 days,
+    // with the given parameters added or replacing the defaults
+    fn search_query(overrides: &[(&str, &str)]) -> Vec<(String, String)> {
+        let end = (100 * DAY).to_string();
+        let mut query: Vec<(String, String)> = [
+            ("regionID", "1"),
+            ("timeRangeStart", "0"),
+            ("timeRangeEnd", end.as_str()),
+            ("numberDays", "2"),
+            ("sortOrder", "price-asc"),
+            ("page", "1"),
+            ("pageSize", "10"),
+            ("priceRangeWidth", "100"),
+            ("minFreeKilometerWidth", "100"),
+        ]
+        .into_iter()
+        .filter(|(name, _)| !overrides.iter().any(|(overridden, _)| overridden == name))
+        .chain(overrides.iter().copied())
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        query.retain(|(_, value)| !value.is_empty());
+        query
+    }
+
+    fn search_params(overrides: &[(&str, &str)]) -> SearchParams {
+        SearchParams::parse(&search_query(overrides), false).unwrap()
+    }
+
+    #[test]
+    fn each_facet_ignores_only_its_own_filter() {
+        let offers = vec![
+            Offer { price: 50, ..offer() },
+            Offer { price: 150, ..offer() },
+            Offer {
+                price: 50,
+                carType: CarType::Luxury,
+                ..offer()
+            },
+        ];
+        let params = search_params(&[("maxPrice", "100"), ("carType", "small")]);
+        let facets = compute_facets(&filtered(&offers), &params);
+
+        // Both small offers, whatever their price
+        let ranges: Vec<(u32, u32, u32)> =
+            facets.priceRanges.iter().map(|range| (range.start, range.end, range.count)).collect();
+        assert_eq!(ranges, [(0, 100, 1), (100, 200, 1)]);
+        // Both cheap offers, whatever their type
+        let counts = &facets.carTypeCounts;
+        assert_eq!((counts.small, counts.luxury), (1, 1));
+        // Only the cheap small offer matches both filters
+        assert_eq!(facets.vollkaskoCount.trueCount + facets.vollkaskoCount.falseCount, 1);
+    }
+
+    #[test]
+    fn seats_count_ignores_only_the_seat_filters() {
+        let seated = |number_seats, price| Offer {
+            numberSeats: number_seats,
+            price,
+            ..offer()
+        };
+        let offers = [seated(5, 50), seated(2, 50), seated(5, 60), seated(7, 50), seated(4, 150)];
+        let seats_count = |overrides: &[(&str, &str)]| -> Vec<(u8, u32)> {
+            let facets = compute_facets(&filtered(&offers), &search_params(overrides));
+            facets.seatsCount.iter().map(|seats| (seats.numberSeats, seats.count)).collect()
+        };
+
+        // Fewest seats first, the expensive offer is left out by maxPrice
+        assert_eq!(seats_count(&[("maxPrice", "100")]), [(2, 1), (5, 2), (7, 1)]);
+        assert_eq!(seats_count(&[("maxPrice", "100"), ("minNumberSeats", "6")]), [(2, 1), (5, 2), (7, 1)]);
+        assert_eq!(seats_count(&[("maxPrice", "100"), ("numberSeats", "5")]), [(2, 1), (5, 2), (7, 1)]);
+        assert_eq!(seats_count(&[("numberSeats", "5")]), [(2, 1), (4, 1), (5, 2), (7, 1)]);
+        assert!(seats_count(&[("minPrice", "500")]).is_empty());
+    }
+
+    fn cache_of(offers: &[Offer]) -> OfferCache {
+        let mut cache = OfferCache::default();
+        cache.insert(offers.to_vec());
+        cache
+    }
+
+    // IDs of the offers query_offers returns for the search, in argument order
+    fn matching(offers: &[Offer], overrides: &[(&str, &str)]) -> Vec<Uuid> {
+        let cache = cache_of(offers);
+        let params = search_params(overrides);
+        let found: HashSet<Uuid> = query_offers(&cache, &RegionTree::default(), &params)
+            .iter()
+            .filter(|offer| params.filters.matches(offer))
+            .map(|offer| offer.ID)
+            .collect();
+        offers.iter().map(|offer| offer.ID).filter(|id| found.contains(id)).collect()
+    }
+
+    fn lasting(start_day: i64, days: i64) -> Offer {
+        Offer {
+            startDate: start_day * DAY,
+            endDate: (start_day + days) * DAY,
+            ..offer()
+        }
+    }
+
+    #[test]
+    fn number_days_needs_the_exact_duration_inside_the_time_range() {
+        let inside = lasting(10, 2);
+        let longer = lasting(10, 3);
+        let ends_after = lasting(19, 2);
+        let starts_before = lasting(4, 2);
+        let offers = [inside.clone(), longer, ends_after, starts_before];
+        let (start, end) = ((5 * DAY).to_string(), (20 * DAY).to_string());
+        let range = [("timeRangeStart", start.as_str()), ("timeRangeEnd", end.as_str()), ("numberDays", "2")];
+        assert_eq!(matching(&offers, &range), [inside.ID]);
+    }
+
+    // Percent-encodes everything but the unreserved characters of a query value
+    fn encode(value: &str) -> String {
+        value
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect()
+    }
+
+    // URI of the search of search_query
+    fn search_uri(overrides: &[(&str, &str)]) -> String {
+        let pairs: Vec<String> = search_query(overrides)
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, encode(value)))
+            .collect();
+        format!("/api/offers?{}", pairs.join("&"))
+    }
+
+    async fn post_offers(router: &Router, offers: &[Offer]) {
+        let body = serde_json::to_value(offers).unwrap();
+        let response = send(router, json_request("POST", "/api/offers", &body)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Body of a successful search through the router
+    async fn search(router: &Router, overrides: &[(&str, &str)]) -> serde_json::Value {
+        let response = send(router, request("GET", &search_uri(overrides))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        body_json(response).await
+    }
+
+    fn result_ids(result: &serde_json::Value) -> Vec<String> {
+        let offers = result["offers"].as_array().unwrap();
+        offers.iter().map(|offer| offer["ID"].as_str().unwrap().to_string()).collect()
+    }
+
+    fn ids(offers: &[Offer]) -> Vec<String> {
+        offers.iter().map(|offer| offer.ID.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn pages_start_at_one_and_end_empty() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let offers = offers_priced(&[10, 20, 30, 40, 50]);
+        post_offers(&router, &offers).await;
+
+        let response = send(&router, request("GET", &search_uri(&[("page", "0")]))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_json(response).await["field"], "page");
+
+        let first = search(&router, &[("page", "1"), ("pageSize", "3")]).await;
+        assert_eq!(result_ids(&first), ids(&offers[..3]));
+        // The last page only holds what is left
+        let last = search(&router, &[("page", "2"), ("pageSize", "3")]).await;
+        assert_eq!(result_ids(&last), ids(&offers[3..]));
+        let past = search(&router, &[("page", "3"), ("pageSize", "3")]).await;
+        assert!(result_ids(&past).is_empty());
+        assert_eq!(past["totalOffers"], 5);
+    }
+
+    #[tokio::test]
+    async fn posting_several_offers_persists_every_one() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[]);
+        let offers: Vec<Offer> = (1..=3)
+            .map(|region_id| Offer {
+                mostSpecificRegionID: region_id,
+                ..offer()
+            })
+            .collect();
+        post_offers(&router, &offers).await;
+
+        assert_eq!(state.cache.read().unwrap().len(), 3);
+        for offer in &offers {
+            let stored = read_offer(&state.db, state.shards, &offer.ID).unwrap().unwrap();
+            assert_eq!(stored.mostSpecificRegionID, offer.mostSpecificRegionID);
+        }
+    }
+
+    #[tokio::test]
+    async fn get_by_id_finds_the_offer_and_rejects_unknown_or_malformed_ids() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let stored = offer();
+        post_offers(&router, std::slice::from_ref(&stored)).await;
+
+        let response = send(&router, request("GET", &format!("/api/offers/{}", stored.ID))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut found = body_json(response).await;
+        // Stamped on write
+        assert!(found["createdAt"].is_u64());
+        found["createdAt"] = serde_json::Value::Null;
+        assert_eq!(found, serde_json::to_value(&stored).unwrap());
+
+        let response = send(&router, request("GET", &format!("/api/offers/{}", Uuid::new_v4()))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = send(&router, request("GET", "/api/offers/not-a-uuid")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_json(response).await["field"], "id");
+    }
+
+    #[tokio::test]
+    async fn delete_removes_the_offer_from_the_database_and_the_cache() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[]);
+        let offers = [offer(), offer()];
+        post_offers(&router, &offers).await;
+
+        let uri = format!("/api/offers/{}", offers[0].ID);
+        assert_eq!(send(&router, request("DELETE", &uri)).await.status(), StatusCode::NO_CONTENT);
+        assert!(read_offer(&state.db, state.shards, &offers[0].ID).unwrap().is_none());
+        let remaining: Vec<Uuid> = state.cache.read().unwrap().region(1).iter().map(|offer| offer.ID).collect();
+        assert_eq!(remaining, [offers[1].ID]);
+        assert_eq!(send(&router, request("GET", &uri)).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn health_answers_ok_when_the_database_responds() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let response = send(&router, request("GET", "/health")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await, serde_json::json!({ "status": "ok" }));
+    }
+
+    fn region_index(db: &Database) -> HashSet<Vec<u8>> {
+        let index = db.cf_handle(CF_REGION_INDEX).unwrap();
+        db.iterator_cf(&index, IteratorMode::Start).map(|item| item.unwrap().0.to_vec()).collect()
+    }
+
+    #[tokio::test]
+    async fn region_index_follows_writes_and_deletes() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[]);
+        let kept = offer();
+        let deleted = Offer {
+            mostSpecificRegionID: 2,
+            ..offer()
+        };
+        post_offers(&router, &[kept.clone(), deleted.clone()]).await;
+        let expected: HashSet<Vec<u8>> = [&kept, &deleted]
+            .iter()
+            .map(|offer| region_index_key(offer.mostSpecificRegionID, &offer.ID))
+            .collect();
+        assert_eq!(region_index(&state.db), expected);
+
+        send(&router, request("DELETE", &format!("/api/offers/{}", deleted.ID))).await;
+        assert_eq!(region_index(&state.db), HashSet::from([region_index_key(1, &kept.ID)]));
+    }
+
+    #[test]
+    fn scan_windows_only_covers_starts_that_can_match() {
+        let offers: Vec<Offer> = [3, 5, 18, 19].into_iter().map(|day| lasting(day, 2)).collect();
+        let cache = cache_of(&offers);
+        let (start, end) = ((5 * DAY).to_string(), (20 * DAY).to_string());
+        let params = search_params(&[("timeRangeStart", start.as_str()), ("timeRangeEnd", end.as_str())]);
+        let windows = scan_windows(
+            &cache,
+            &RegionTree::default(),
+            params.region,
+            params.time_range_start,
+            params.time_range_end,
+            params.days,
+            params.time_match,
+        );
+        // Starting before the range or too late to last 2 days before its end
+        let scanned: Vec<i64> = windows.into_iter().flatten().map(|offer| offer.startDate / DAY).collect();
+        assert_eq!(scanned, [5, 18]);
+    }
+
+    // Run with and without the parallel feature, both have to produce this same order
+    #[test]
+    fn query_offers_returns_the_matches_in_cache_order() {
+        let mut rng = Rng::new(21);
+        let offers: Vec<Offer> = (0..5000)
+            .map(|_| Offer {
+                mostSpecificRegionID: 1,
+                ..random_offer(&mut rng, 1)
+            })
+            .collect();
+        let cache = cache_of(&offers);
+        let start = offers.iter().map(|offer| offer.startDate).min().unwrap();
+        let end = start + 120 * DAY;
+        let (start_param, end_param) = (start.to_string(), end.to_string());
+        let params = search_params(&[
+            ("timeRangeStart", start_param.as_str()),
+            ("timeRangeEnd", end_param.as_str()),
+            ("numberDays", "3"),
+        ]);
+
+        let found: Vec<Uuid> = query_offers(&cache, &RegionTree::default(), &params)
+            .iter()
+            .map(|offer| offer.ID)
+            .collect();
+        let expected: Vec<Uuid> = cache
+            .region(1)
+            .iter()
+            .filter(|offer| offer.startDate >= start && offer.endDate <= end && offer.endDate - offer.startDate == 3 * DAY)
+            .map(|offer| offer.ID)
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(found, expected);
+    }
+
+    #[tokio::test]
+    async fn total_offers_counts_every_match_and_not_just_the_page() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        post_offers(&router, &offers_priced(&[10, 20, 30, 40, 500])).await;
+
+        let result = search(&router, &[("pageSize", "2")]).await;
+        assert_eq!(result_ids(&result).len(), 2);
+        assert_eq!(result["totalOffers"], 5);
+        // Only matches of the optional filters count
+        let result = search(&router, &[("pageSize", "2"), ("maxPrice", "100")]).await;
+        assert_eq!(result["totalOffers"], 4);
+    }
+
+    #[test]
+    fn min_price_is_inclusive_and_max_price_exclusive() {
+        let offers = offers_priced(&[19, 20, 39, 40]);
+        let prices = |overrides: &[(&str, &str)]| -> Vec<u16> {
+            let found = matching(&offers, overrides);
+            offers.iter().filter(|offer| found.contains(&offer.ID)).map(|offer| offer.price).collect()
+        };
+        assert_eq!(prices(&[("minPrice", "20")]), [20, 39, 40]);
+        assert_eq!(prices(&[("maxPrice", "40")]), [19, 20, 39]);
+        assert_eq!(prices(&[("minPrice", "20"), ("maxPrice", "40")]), [20, 39]);
+        assert!(prices(&[("minPrice", "20"), ("maxPrice", "20")]).is_empty());
+    }
+
+    #[test]
+    fn a_region_matches_the_offers_of_its_descendants() {
+        let leaf = |id| Region {
+            id,
+            name: String::new(),
+            subregions: Vec::new(),
+        };
+        let child = Region {
+            subregions: vec![leaf(3)],
+            ..leaf(2)
+        };
+        let tree = RegionTree::from_root(&Region {
+            subregions: vec![child, leaf(4)],
+            ..leaf(1)
+        });
+        let offers: Vec<Offer> = (1..=4)
+            .map(|region_id| Offer {
+                mostSpecificRegionID: region_id,
+                ..offer()
+            })
+            .collect();
+        let cache = cache_of(&offers);
+
+        let regions = |region_id: &str| -> HashSet<i32> {
+            let params = search_params(&[("regionID", region_id)]);
+            query_offers(&cache, &tree, &params).iter().map(|offer| offer.mostSpecificRegionID).collect()
+        };
+        assert_eq!(regions("1"), HashSet::from([1, 2, 3, 4]));
+        assert_eq!(regions("2"), HashSet::from([2, 3]));
+        assert_eq!(regions("3"), HashSet::from([3]));
+    }
+
+    fn patch_request(id: &Uuid, patch: serde_json::Value) -> Request<Body> {
+        json_request("PATCH", &format!("/api/offers/{}", id), &patch)
+    }
+
+    #[tokio::test]
+    async fn patch_changes_only_the_given_fields() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[]);
+        let stored = offer();
+        post_offers(&router, std::slice::from_ref(&stored)).await;
+
+        let response = send(&router, patch_request(&stored.ID, serde_json::json!({ "price": 250 }))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await["price"], 250);
+        let updated = read_offer(&state.db, state.shards, &stored.ID).unwrap().unwrap();
+        assert_eq!(updated.price, 250);
+        assert_eq!(updated.numberSeats, stored.numberSeats);
+        assert_eq!(updated.data, stored.data);
+    }
+
+    #[tokio::test]
+    async fn patch_moves_an_offer_to_another_region() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[("DB_SHARDS", "4")]);
+        let stored = offer();
+        post_offers(&router, std::slice::from_ref(&stored)).await;
+
+        let response = send(&router, patch_request(&stored.ID, serde_json::json!({ "mostSpecificRegionID": 2 }))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_offer(&state.db, state.shards, &stored.ID).unwrap().unwrap().mostSpecificRegionID, 2);
+        assert_eq!(region_index(&state.db), HashSet::from([region_index_key(2, &stored.ID)]));
+        let cache = state.cache.read().unwrap();
+        assert!(cache.region(1).is_empty());
+        assert_eq!(cache.region(2)[0].ID, stored.ID);
+    }
+
+    #[tokio::test]
+    async fn patch_rejects_invalid_results_and_unknown_offers() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[]);
+        let stored = offer();
+        post_offers(&router, std::slice::from_ref(&stored)).await;
+
+        let response = send(&router, patch_request(&stored.ID, serde_json::json!({ "price": 0 }))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_json(response).await["field"], "price");
+        assert_eq!(read_offer(&state.db, state.shards, &stored.ID).unwrap().unwrap().price, stored.price);
+
+        let response = send(&router, patch_request(&Uuid::new_v4(), serde_json::json!({ "price": 250 }))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    // Status and error body of a search expected to fail
+    async fn search_error(router: &Router, overrides: &[(&str, &str)]) -> (StatusCode, serde_json::Value) {
+        let response = send(router, request("GET", &search_uri(overrides))).await;
+        (response.status(), body_json(response).await)
+    }
+
+    #[tokio::test]
+    async fn missing_params_are_422_and_unparsable_ones_400() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+
+        let (status, body) = search_error(&router, &[("regionID", "")]).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["fields"], serde_json::json!(["regionID"]));
+        let (status, body) = search_error(&router, &[("regionID", "north")]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["fields"], serde_json::json!(["regionID"]));
+    }
+
+    #[tokio::test]
+    async fn page_size_is_bounded() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let max = MAX_PAGE_SIZE.to_string();
+        let over = (MAX_PAGE_SIZE + 1).to_string();
+
+        search(&router, &[("pageSize", max.as_str())]).await;
+        for page_size in ["0", over.as_str()] {
+            let (status, body) = search_error(&router, &[("pageSize", page_size)]).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["field"], "pageSize");
+        }
+    }
+
+    #[tokio::test]
+    async fn reposting_an_offer_leaves_one_index_entry() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[]);
+        let stored = offer();
+        post_offers(&router, std::slice::from_ref(&stored)).await;
+        post_offers(&router, std::slice::from_ref(&stored)).await;
+        assert_eq!(region_index(&state.db), HashSet::from([region_index_key(1, &stored.ID)]));
+
+        // Moving to another region replaces the entry
+        let moved = Offer {
+            mostSpecificRegionID: 2,
+            ..stored.clone()
+        };
+        post_offers(&router, &[moved]).await;
+        assert_eq!(region_index(&state.db), HashSet::from([region_index_key(2, &stored.ID)]));
+        assert_eq!(state.cache.read().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_sort_orders_are_rejected() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        for sort_order in ["price-desc", "kilometers-asc", "start-date-asc", "created-desc"] {
+            search(&router, &[("sortOrder", sort_order)]).await;
+        }
+        for sort_order in ["price", "PRICE-ASC", "random"] {
+            let (status, body) = search_error(&router, &[("sortOrder", sort_order)]).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["fields"], serde_json::json!(["sortOrder"]));
+        }
+    }
+
+    #[tokio::test]
+    async fn cleanup_of_a_region_deletes_only_its_offers() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[("DB_SHARDS", "2")]);
+        let in_region = |region_id| Offer {
+            mostSpecificRegionID: region_id,
+            ..offer()
+        };
+        // Region 4 shares the shard of region 2
+        let (deleted, kept) = ([in_region(2), in_region(2)], [in_region(1), in_region(4)]);
+        post_offers(&router, &[deleted.as_slice(), kept.as_slice()].concat()).await;
+
+        let response = send(&router, request("DELETE", "/api/offers?regionID=2")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await["deleted"], 2);
+        for offer in &deleted {
+            assert!(read_offer(&state.db, state.shards, &offer.ID).unwrap().is_none());
+        }
+        for offer in &kept {
+            assert!(read_offer(&state.db, state.shards, &offer.ID).unwrap().is_some());
+        }
+        let expected: HashSet<Vec<u8>> = kept
+            .iter()
+            .map(|offer| region_index_key(offer.mostSpecificRegionID, &offer.ID))
+            .collect();
+        assert_eq!(region_index(&state.db), expected);
+        let cache = state.cache.read().unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.region(2).is_empty());
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_413() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[("BODY_LIMIT_BYTES", "1024")]);
+        let offers = serde_json::to_value([offer(), offer(), offer()]).unwrap();
+        assert!(offers.to_string().len() > 1024);
+
+        let response = send(&router, json_request("POST", "/api/offers", &offers)).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(state.cache.read().unwrap().len(), 0);
+        // A single offer fits
+        post_offers(&router, &[offer()]).await;
+    }
+
+    // Offers in region 1 lasting 2 days and differing in every filtered attribute
+    fn varied_offers(count: u16) -> Vec<Offer> {
+        let car_types = [CarType::Small, CarType::Sports, CarType::Luxury, CarType::Family];
+        (0..count)
+            .map(|i| Offer {
+                price: 10 + i * 37 % 400,
+                carType: car_types[i as usize % 4],
+                numberSeats: 2 + (i % 6) as u8,
+                hasVollkasko: i % 3 == 0,
+                freeKilometers: i * 53 % 1000,
+                ..lasting(10 + i64::from(i % 5), 2)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn count_equals_the_total_offers_of_the_search() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        post_offers(&router, &varied_offers(40)).await;
+
+        let filters: [&[(&str, &str)]; 4] = [
+            &[],
+            &[("minPrice", "100"), ("maxPrice", "300")],
+            &[("carType", "sports"), ("onlyVollkasko", "true")],
+            &[("minNumberSeats", "4"), ("minFreeKilometer", "400")],
+        ];
+        for overrides in filters {
+            let total = search(&router, overrides).await["totalOffers"].clone();
+            assert!(total.as_u64().unwrap() > 0);
+            let uri = search_uri(overrides).replacen("/api/offers?", "/api/offers/count?", 1);
+            let response = send(&router, request("GET", &uri)).await;
+            assert_eq!(body_json(response).await["count"], total, "{:?}", overrides);
+        }
+    }
+
+    #[tokio::test]
+    async fn zero_bucket_widths_are_rejected() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        for width in ["priceRangeWidth", "minFreeKilometerWidth"] {
+            let (status, body) = search_error(&router, &[(width, "0")]).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["field"], width);
+        }
+    }
+
+    #[test]
+    fn number_seats_matches_exactly_and_excludes_min_number_seats() {
+        let seats = |number_seats| Offer {
+            numberSeats: number_seats,
+            ..offer()
+        };
+        let offers = [seats(4), seats(5), seats(7)];
+        assert_eq!(matching(&offers, &[("numberSeats", "5")]), [offers[1].ID]);
+        assert_eq!(matching(&offers, &[("minNumberSeats", "5")]), [offers[1].ID, offers[2].ID]);
+
+        let query = search_query(&[("numberSeats", "5"), ("minNumberSeats", "4")]);
+        let e = SearchParams::parse(&query, false).err().unwrap();
+        assert_eq!(e.status, StatusCode::BAD_REQUEST);
+        assert_eq!(e.body.fields, ["numberSeats", "minNumberSeats"]);
+
+        // An unparsable or out of range count is rejected instead of dropping the filter
+        for invalid in ["abc", "300", "-1", "4.5"] {
+            let e = SearchParams::parse(&search_query(&[("numberSeats", invalid)]), false).err().unwrap();
+            assert_eq!(e.status, StatusCode::BAD_REQUEST, "{}", invalid);
+            assert_eq!(e.body.field.as_deref(), Some("numberSeats"));
+        }
+    }
+
+    #[test]
+    fn free_kilometer_bounds_are_inclusive() {
+        let kilometers = |free_kilometers| Offer {
+            freeKilometers: free_kilometers,
+            ..offer()
+        };
+        let offers = [kilometers(99), kilometers(100), kilometers(200), kilometers(201)];
+        let ids = |indexes: &[usize]| -> Vec<Uuid> { indexes.iter().map(|&index| offers[index].ID).collect() };
+        assert_eq!(matching(&offers, &[("maxFreeKilometer", "200")]), ids(&[0, 1, 2]));
+        assert_eq!(matching(&offers, &[("minFreeKilometer", "100"), ("maxFreeKilometer", "200")]), ids(&[1, 2]));
+        assert_eq!(matching(&offers, &[("maxFreeKilometer", "98")]), ids(&[]));
+    }
+
+    #[tokio::test]
+    async fn pages_over_equal_prices_neither_overlap_nor_skip() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let offers = offers_priced(&[100; 7]);
+        post_offers(&router, &offers).await;
+
+        let mut paged = Vec::new();
+        for page in ["1", "2", "3"] {
+            let first = search(&router, &[("page", page), ("pageSize", "3")]).await;
+            // Asking again gives the same page
+            assert_eq!(search(&router, &[("page", page), ("pageSize", "3")]).await["offers"], first["offers"]);
+            paged.extend(result_ids(&first));
+        }
+        // Ties are broken by ID
+        let mut expected = ids(&offers);
+        expected.sort();
+        assert_eq!(paged, expected);
+    }
+
+    #[test]
+    fn offer_stats_summarize_every_offer() {
+        let empty = compute_offer_stats(&OfferCache::default());
+        assert_eq!(empty.totalOffers, 0);
+        assert_eq!((empty.minPrice, empty.maxPrice, empty.avgPrice), (None, None, None));
+
+        let offers = [
+            Offer { price: 10, ..offer() },
+            Offer {
+                price: 40,
+                carType: CarType::Family,
+                mostSpecificRegionID: 2,
+                ..offer()
+            },
+            Offer {
+                price: 25,
+                mostSpecificRegionID: 2,
+                ..offer()
+            },
+        ];
+        let stats = compute_offer_stats(&cache_of(&offers));
+        assert_eq!(stats.totalOffers, 3);
+        assert_eq!((stats.minPrice, stats.maxPrice, stats.avgPrice), (Some(10), Some(40), Some(25.0)));
+        assert_eq!(stats.distinctRegions, 2);
+        assert_eq!((stats.carTypeCounts.small, stats.carTypeCounts.family), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn without_aggregations_the_facets_are_empty() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let offers = varied_offers(10);
+        post_offers(&router, &offers).await;
+
+        let with = search(&router, &[]).await;
+        let without = search(&router, &[("includeAggregations", "false")]).await;
+        assert!(!with["priceRanges"].as_array().unwrap().is_empty());
+        assert_eq!(without["offers"], with["offers"]);
+        assert_eq!(without["totalOffers"], offers.len());
+        let facets = serde_json::to_value(Facets::default()).unwrap();
+        for (name, empty) in facets.as_object().unwrap() {
+            assert_eq!(&without[name], empty, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn inverted_time_ranges_are_rejected() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let (start, end) = ((20 * DAY).to_string(), (10 * DAY).to_string());
+        let (status, body) = search_error(&router, &[("timeRangeStart", start.as_str()), ("timeRangeEnd", end.as_str())]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["fields"], serde_json::json!(["timeRangeStart", "timeRangeEnd"]));
+        // An empty range is fine
+        search(&router, &[("timeRangeStart", start.as_str()), ("timeRangeEnd", start.as_str())]).await;
+    }
+
+    fn import_request(snapshot: impl Into<Body>) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri("/api/offers/import")
+            .header("content-type", "application/octet-stream")
+            .body(snapshot.into())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn export_then_import_reproduces_the_offers() {
+        let (source_dir, target_dir) = (TempDir::new(), TempDir::new());
+        let (_, source) = test_app(&source_dir, &[]);
+        let (state, target) = test_app(&target_dir, &[]);
+        let offers = varied_offers(5);
+        post_offers(&source, &offers).await;
+        let replaced = offer();
+        post_offers(&target, std::slice::from_ref(&replaced)).await;
+
+        let response = send(&source, request("GET", "/api/offers/export")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let snapshot = body_bytes(response).await;
+        let response = send(&target, import_request(snapshot)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await["imported"], 5);
+
+        // The snapshot replaces what was there
+        assert!(read_offer(&state.db, state.shards, &replaced.ID).unwrap().is_none());
+        assert_eq!(state.cache.read().unwrap().len(), 5);
+        for offer in &offers {
+            let uri = format!("/api/offers/{}", offer.ID);
+            let (exported, imported) = (send(&source, request("GET", &uri)).await, send(&target, request("GET", &uri)).await);
+            assert_eq!(body_json(imported).await, body_json(exported).await);
+        }
+
+        // A length prefix promising more bytes than follow
+        let truncated = import_request(vec![9, 0, 0, 0, 1]);
+        assert_eq!(send(&target, truncated).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(state.cache.read().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn concurrent_upserts_leave_no_stale_index_entries() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[("DB_SHARDS", "4")]);
+        let stored = offer();
+        post_offers(&router, std::slice::from_ref(&stored)).await;
+
+        // Every upsert moves the offer to another region, the writes themselves run on the
+        // blocking pool and so in parallel
+        let moves: Vec<Offer> = (0..32)
+            .map(|i| Offer {
+                mostSpecificRegionID: i % 8,
+                ..stored.clone()
+            })
+            .collect();
+        futures_util::future::join_all(moves.chunks(1).map(|moved| post_offers(&router, moved))).await;
+
+        let region_id = read_offer(&state.db, state.shards, &stored.ID).unwrap().unwrap().mostSpecificRegionID;
+        assert_eq!(region_index(&state.db), HashSet::from([region_index_key(region_id, &stored.ID)]));
+        let cache = state.cache.read().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.region(region_id)[0].ID, stored.ID);
+    }
+
+    #[test]
+    fn reads_find_offers_in_every_shard() {
+        let dir = TempDir::new();
+        let config = DbConfig {
+            shards: 4,
+            ..DbConfig::default()
+        };
+        let shards = Shards::new(config.shards);
+        let db = init_db(dir.path(), &config).unwrap();
+        let mut offers: Vec<Offer> = (0..8)
+            .map(|region_id| Offer {
+                mostSpecificRegionID: region_id,
+                ..offer()
+            })
+            .collect();
+        write_offers(&db, shards, config.write_retry(), Wal::Enabled, DataCompression::None, &mut offers).unwrap();
+
+        for offer in &offers {
+            let (shard, _) = read_offer_value(&db, shards, &offer.ID).unwrap().unwrap();
+            assert_eq!(shard, shards.of_region(offer.mostSpecificRegionID));
+        }
+        let mut ids: Vec<Uuid> = offers.iter().map(|offer| offer.ID).collect();
+        ids.push(Uuid::new_v4());
+        let found = read_offers(&db, shards, &ids).unwrap();
+        assert_eq!(found.keys().copied().collect::<HashSet<Uuid>>(), ids[..8].iter().copied().collect());
+    }
+
+    fn with_header(mut request: Request<Body>, name: header::HeaderName, value: &str) -> Request<Body> {
+        request.headers_mut().insert(name, value.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn get_by_id_answers_304_only_for_the_current_etag() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let stored = offer();
+        post_offers(&router, std::slice::from_ref(&stored)).await;
+        let uri = format!("/api/offers/{}", stored.ID);
+
+        let response = send(&router, request("GET", &uri)).await;
+        let etag = response.headers()[header::ETAG].to_str().unwrap().to_string();
+        let response = send(&router, with_header(request("GET", &uri), header::IF_NONE_MATCH, &etag)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(body_bytes(response).await.is_empty());
+
+        // Any change makes the old tag stale
+        send(&router, patch_request(&stored.ID, serde_json::json!({ "price": 250 }))).await;
+        let response = send(&router, with_header(request("GET", &uri), header::IF_NONE_MATCH, &etag)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[header::ETAG], etag.as_str());
+        assert_eq!(body_json(response).await["price"], 250);
+    }
+
+    #[test]
+    fn car_types_can_be_listed_or_repeated() {
+        let offers: Vec<Offer> = [CarType::Small, CarType::Sports, CarType::Luxury, CarType::Family]
+            .into_iter()
+            .map(|car_type| Offer {
+                carType: car_type,
+                ..offer()
+            })
+            .collect();
+        let expected = [offers[0].ID, offers[2].ID];
+        assert_eq!(matching(&offers, &[("carType", "small,luxury")]), expected);
+        assert_eq!(matching(&offers, &[("carType", "small"), ("carType", "luxury")]), expected);
+        assert_eq!(matching(&offers, &[("carType", "luxury,small"), ("carType", "small")]), expected);
+
+        let query = search_query(&[("carType", "small,van")]);
+        assert_eq!(SearchParams::parse(&query, false).err().unwrap().body.field.as_deref(), Some("carType"));
+    }
+
+    // Holds the write lock well past the request timeout
+    async fn release_later(guard: tokio::sync::OwnedMutexGuard<()>) {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(guard);
+    }
+
+    #[tokio::test]
+    async fn requests_waiting_too_long_time_out_except_cleanup_and_import() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[("REQUEST_TIMEOUT_MS", "50")]);
+        let body = serde_json::to_value([offer()]).unwrap();
+
+        // Writes queue behind the held write lock
+        let guard = state.write_lock.clone().lock_owned().await;
+        let response = send(&router, json_request("POST", "/api/offers", &body)).await;
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+
+        let (cleanup, ()) = tokio::join!(send(&router, request("DELETE", "/api/offers")), release_later(guard));
+        assert_eq!(cleanup.status(), StatusCode::OK);
+
+        let guard = state.write_lock.clone().lock_owned().await;
+        let snapshot = encode_snapshot([&offer()]).unwrap();
+        let (import, ()) = tokio::join!(send(&router, import_request(snapshot)), release_later(guard));
+        assert_eq!(import.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn only_the_page_copies_data_and_responses_carry_their_length() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        post_offers(&router, &varied_offers(50)).await;
+
+        // The search runs on this thread
+        let copied_before = COPIED_DATA.with(|copied| copied.get());
+        let response = send(&router, request("GET", &search_uri(&[("pageSize", "5")]))).await;
+        assert_eq!(COPIED_DATA.with(|copied| copied.get()) - copied_before, 5);
+
+        // Uncompressed, hyper sends the buffered body with its length
+        let length: usize = response.headers()[header::CONTENT_LENGTH].to_str().unwrap().parse().unwrap();
+        let body = body_bytes(response).await;
+        assert_eq!(length, body.len());
+        let result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result_ids(&result).len(), 5);
+        assert_eq!(result["totalOffers"], 50);
+
+        // The compressed length differs, so only the uncompressed response has one
+        let gzip = with_header(request("GET", &search_uri(&[])), header::ACCEPT_ENCODING, "gzip");
+        let response = send(&router, gzip).await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+    }
+
+    #[tokio::test]
+    async fn facets_endpoint_equals_the_facets_of_the_search() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        post_offers(&router, &varied_offers(30)).await;
+
+        for overrides in [&[][..], &[("carType", "sports,family"), ("minPrice", "100")][..]] {
+            let result = search(&router, overrides).await;
+            let uri = search_uri(overrides).replacen("/api/offers?", "/api/offers/facets?", 1);
+            let facets = body_json(send(&router, request("GET", &uri)).await).await;
+            let facets = facets.as_object().unwrap();
+            // Every block but the opt-in regionCounts
+            assert_eq!(facets.len(), 7);
+            for (name, value) in facets {
+                assert_eq!(&result[name], value, "{}", name);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn head_sends_the_count_without_a_body() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        post_offers(&router, &offers_priced(&[10, 20, 300])).await;
+
+        let response = send(&router, request("HEAD", &search_uri(&[("maxPrice", "100")]))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-total-count"], "2");
+        assert!(body_bytes(response).await.is_empty());
+
+        let response = send(&router, request("HEAD", &search_uri(&[("regionID", "")]))).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body_bytes(response).await.is_empty());
+    }
+
+    #[test]
+    fn only_vollkasko_false_does_not_filter() {
+        let offers = [
+            Offer {
+                hasVollkasko: true,
+                ..offer()
+            },
+            offer(),
+        ];
+        let all = [offers[0].ID, offers[1].ID];
+        assert_eq!(matching(&offers, &[("onlyVollkasko", "true")]), [offers[0].ID]);
+        assert_eq!(matching(&offers, &[("onlyVollkasko", "false")]), all);
+        assert_eq!(matching(&offers, &[]), all);
+    }
+
+    #[test]
+    fn partial_sort_matches_the_full_sort() {
+        let mut rng = Rng::new(72);
+        // Few distinct prices and kilometers, so there are plenty of ties
+        let offers: Vec<Offer> = (0..200)
+            .map(|_| {
+                let offer = random_offer(&mut rng, 4);
+                Offer {
+                    price: offer.price % 20,
+                    freeKilometers: offer.freeKilometers % 20,
+                    createdAt: Some((rng.next_u64() % 50) as i64),
+                    ..offer
+                }
+            })
+            .collect();
+        let sort_orders = [
+            SortOrder::PriceAsc,
+            SortOrder::PriceDesc,
+            SortOrder::KilometersAsc,
+            SortOrder::KilometersDesc,
+            SortOrder::StartDateAsc,
+            SortOrder::StartDateDesc,
+            SortOrder::CreatedDesc,
+        ];
+        for sort_order in sort_orders {
+            let mut full = filtered(&offers);
+            sort_offers(&mut full, sort_order, offers.len());
+            for limit in [0, 1, 7, 100, 199, 200, 300] {
+                let mut partial = filtered(&offers);
+                sort_offers(&mut partial, sort_order, limit);
+                let limit = limit.min(offers.len());
+                let ids = |offers: &[FilteredOffer]| -> Vec<Uuid> { offers[..limit].iter().map(|offer| offer.ID).collect() };
+                assert_eq!(ids(&partial), ids(&full), "{:?} limit {}", sort_order, limit);
+            }
+        }
+    }
+
+    #[test]
+    fn number_days_ranges_are_inclusive_and_exclude_number_days() {
+        let offers: Vec<Offer> = (1..=4).map(|days| lasting(10, days)).collect();
+        let ids = |days: &[usize]| -> Vec<Uuid> { days.iter().map(|&days| offers[days - 1].ID).collect() };
+        let range = |min: &'static str, max: &'static str| [("numberDays", ""), ("minNumberDays", min), ("maxNumberDays", max)];
+        assert_eq!(matching(&offers, &range("2", "3")), ids(&[2, 3]));
+        assert_eq!(matching(&offers, &range("3", "")), ids(&[3, 4]));
+        assert_eq!(matching(&offers, &range("", "1")), ids(&[1]));
+
+        let error = |overrides: &[(&str, &str)]| SearchParams::parse(&search_query(overrides), false).err().unwrap();
+        // The default numberDays stays in
+        let e = error(&[("minNumberDays", "2")]);
+        assert_eq!(e.body.fields, ["numberDays", "minNumberDays", "maxNumberDays"]);
+        let e = error(&range("3", "2"));
+        assert_eq!(e.body.fields, ["minNumberDays", "maxNumberDays"]);
+        assert_eq!(error(&range("0", "2")).body.field.as_deref(), Some("minNumberDays"));
+    }
+
+    #[test]
+    fn fixed_price_bands_include_their_start_and_every_band() {
+        let offers = offers_priced(&[5, 10, 19, 20, 50, 60]);
+        let fixed = |boundaries: &[u32]| PriceBuckets::Fixed(boundaries.to_vec());
+        assert_eq!(price_ranges(&offers, fixed(&[10, 20, 50])), [(10, 20, 2), (20, 50, 1)]);
+        // Empty bands are listed too, prices outside the boundaries in none
+        assert_eq!(price_ranges(&offers, fixed(&[10, 20, 30, 50])), [(10, 20, 2), (20, 30, 1), (30, 50, 0)]);
+    }
+
+    #[test]
+    fn price_buckets_have_to_increase_strictly() {
+        assert!(PriceBuckets::parse_fixed("0, 100,250").is_ok());
+        for invalid in ["100", "100,100", "250,100", "0,cheap", ""] {
+            let e = PriceBuckets::parse_fixed(invalid).unwrap_err();
+            assert_eq!(e.body.field.as_deref(), Some("priceBuckets"), "{:?}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_lines_are_every_page_of_the_search() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        // More matches than one stream chunk, with price ties and some offers of another duration
+        let offers: Vec<Offer> = (0..2400u16)
+            .map(|i| Offer {
+                price: 1 + i % 250,
+                numberSeats: 2 + (i % 6) as u8,
+                ..lasting(10 + i64::from(i % 5), 2 + i64::from(i % 40 == 0))
+            })
+            .collect();
+        post_offers(&router, &offers).await;
+        let filter = [("minNumberSeats", "4"), ("sortOrder", "price-desc")];
+
+        let mut paged = Vec::new();
+        for page in 1.. {
+            let page = page.to_string();
+            let result = search(&router, &[filter[0], filter[1], ("page", &page), ("pageSize", "100")]).await;
+            let offers = result["offers"].as_array().unwrap().clone();
+            if offers.is_empty() {
+                break;
+            }
+            paged.extend(offers);
+        }
+        assert!(paged.len() > STREAM_CHUNK_SIZE);
+
+        let response = send(&router, request("GET", &search_uri(&[filter[0], filter[1], ("stream", "true")]))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/x-ndjson");
+        let body = body_bytes(response).await;
+        let streamed: Vec<serde_json::Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(streamed, paged);
+    }
+
+    fn admin_header() -> header::HeaderName {
+        header::HeaderName::from_static(ADMIN_TOKEN_HEADER)
+    }
+
+    #[tokio::test]
+    async fn compaction_needs_the_admin_token() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[("ADMIN_TOKEN", "s3cret")]);
+        let response = send(&router, request("POST", "/api/admin/compact")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let wrong = with_header(request("POST", "/api/admin/compact"), admin_header(), "s3cre");
+        assert_eq!(send(&router, wrong).await.status(), StatusCode::UNAUTHORIZED);
+
+        let right = with_header(request("POST", "/api/admin/compact"), admin_header(), "s3cret");
+        let response = send(&router, right).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let report = body_json(response).await;
+        let names: Vec<&str> = report["columnFamilies"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|family| family["name"].as_str().unwrap())
+            .collect();
+        assert!(names.contains(&"default") && names.contains(&"idx_region"), "{:?}", names);
+    }
+
+    #[tokio::test]
+    async fn compaction_is_disabled_without_an_admin_token() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let request = with_header(request("POST", "/api/admin/compact"), admin_header(), "");
+        assert_eq!(send(&router, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn filters_match_the_conjunction_of_every_other_filter() {
+        let offers = varied_offers(120);
+        let offers = filtered(&offers);
+        let combinations: [&[(&str, &str)]; 4] = [
+            &[],
+            &[("minNumberSeats", "4"), ("carType", "small,family"), ("onlyVollkasko", "true")],
+            &[("numberSeats", "5"), ("minPrice", "100"), ("maxPrice", "300"), ("maxFreeKilometer", "600")],
+            &[("minPrice", "50"), ("carType", "sports"), ("minFreeKilometer", "200"), ("onlyVollkasko", "false")],
+        ];
+        for overrides in combinations {
+            let f = search_params(overrides).filters;
+            for offer in &offers {
+                let seats = matches_seats(offer, f.min_number_seats, f.number_seats);
+                let price = matches_price(offer, f.min_price, f.max_price);
+                let car_type = matches_car_type(offer, f.car_type);
+                let vollkasko = matches_vollkasko(offer, f.only_vollkasko);
+                let free_kilometer = matches_free_kilometer(offer, f.min_free_kilometer, f.max_free_kilometer);
+                let context = (overrides, offer.ID);
+                assert_eq!(f.matches(offer), seats && price && car_type && vollkasko && free_kilometer, "{:?}", context);
+                assert_eq!(f.matches_except(offer, Dimension::Seats), price && car_type && vollkasko && free_kilometer);
+                assert_eq!(f.matches_except(offer, Dimension::Price), seats && car_type && vollkasko && free_kilometer);
+                assert_eq!(f.matches_except(offer, Dimension::CarType), seats && price && vollkasko && free_kilometer);
+                assert_eq!(f.matches_except(offer, Dimension::Vollkasko), seats && price && car_type && free_kilometer);
+                assert_eq!(f.matches_except(offer, Dimension::FreeKilometer), seats && price && car_type && vollkasko);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn region_counts_split_the_matches_by_descendant() {
+        let leaf = |id| Region {
+            id,
+            name: String::new(),
+            subregions: Vec::new(),
+        };
+        let root = Region {
+            subregions: vec![Region { subregions: vec![leaf(3)], ..leaf(2) }, leaf(4)],
+            ..leaf(1)
+        };
+        let dir = TempDir::new();
+        let state = AppState {
+            regions: std::sync::Arc::new(RegionTree::from_root(&root)),
+            ..test_state(&dir)
+        };
+        let router = crate::app(state, &test_config(&[]));
+        let in_region = |region_id, number_seats| Offer {
+            mostSpecificRegionID: region_id,
+            numberSeats: number_seats,
+            ..offer()
+        };
+        let mut offers = vec![in_region(2, 4), in_region(2, 4), in_region(4, 4), in_region(4, 4)];
+        offers.extend((0..3).map(|_| in_region(3, 4)));
+        // Filtered out, so not counted
+        offers.push(in_region(2, 2));
+        post_offers(&router, &offers).await;
+
+        let counts = |result: serde_json::Value| -> Vec<(i64, i64)> {
+            let counts = result["regionCounts"].as_array().unwrap();
+            counts
+                .iter()
+                .map(|count| (count["regionID"].as_i64().unwrap(), count["count"].as_i64().unwrap()))
+                .collect()
+        };
+        let opt_in = ("includeRegionCounts", "true");
+        let filter = ("minNumberSeats", "4");
+        // Most offers first, ties by region ID
+        assert_eq!(counts(search(&router, &[opt_in, filter]).await), [(3, 3), (2, 2), (4, 2)]);
+        assert_eq!(counts(search(&router, &[opt_in, filter, ("regionID", "2")]).await), [(3, 3), (2, 2)]);
+        assert_eq!(counts(search(&router, &[opt_in, filter, ("regionID", "3")]).await), [(3, 3)]);
+        assert!(search(&router, &[filter]).await.get("regionCounts").is_none());
+    }
+
+    #[tokio::test]
+    async fn nowal_uploads_and_imports_survive_a_reopen() {
+        let (source_dir, target_dir) = (TempDir::new(), TempDir::new());
+        let (_, source) = test_app(&source_dir, &[]);
+        let offers = varied_offers(6);
+        let body = serde_json::to_value(&offers).unwrap();
+        let response = send(&source, json_request("POST", "/api/offers?nowal=true", &body)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let snapshot = body_bytes(send(&source, request("GET", "/api/offers/export")).await).await;
+
+        {
+            let (_, target) = test_app(&target_dir, &[]);
+            let mut import = import_request(snapshot);
+            *import.uri_mut() = "/api/offers/import?nowal=true".parse().unwrap();
+            assert_eq!(send(&target, import).await.status(), StatusCode::OK);
+        }
+        // Both databases are opened again, loading the cache from what reached disk
+        drop(source);
+        for dir in [&source_dir, &target_dir] {
+            let (state, router) = test_app(dir, &[]);
+            assert_eq!(state.cache.read().unwrap().len(), offers.len());
+            let result = search(&router, &[("pageSize", "100")]).await;
+            let mut found = result_ids(&result);
+            let mut expected = ids(&offers);
+            found.sort();
+            expected.sort();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn start_date_orders_break_ties_by_ascending_id() {
+        let ids = sorted_ids(5);
+        let offers: Vec<Offer> = ids
+            .iter()
+            .zip([12, 10, 12, 11, 10])
+            .map(|(&id, start_day)| Offer { ID: id, ..lasting(start_day, 2) })
+            .collect();
+        let order = |sort_order| {
+            let mut sorted = filtered(&offers);
+            sort_offers(&mut sorted, sort_order, offers.len());
+            sorted.iter().map(|offer| offer.ID).collect::<Vec<Uuid>>()
+        };
+        assert_eq!(order(SortOrder::StartDateAsc), [ids[1], ids[4], ids[3], ids[0], ids[2]]);
+        // Only the dates are reversed, equal dates still come in ascending ID order
+        assert_eq!(order(SortOrder::StartDateDesc), [ids[0], ids[2], ids[3], ids[1], ids[4]]);
+    }
+
+    #[tokio::test]
+    async fn a_batch_repeating_an_id_is_rejected_whole() {
+        let dir = TempDir::new();
+        let state = test_state(&dir);
+        let router = offers_router(state.clone());
+        let (first, second, unique) = (offer(), offer(), offer());
+        let repeat = Offer {
+            price: 200,
+            ..first.clone()
+        };
+        let offers = serde_json::to_value([&first, &second, &repeat, &unique, &second]).unwrap();
+
+        let response = send(&router, json_request("POST", "/api/offers", &offers)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error = body_json(response).await;
+        assert_eq!(error["field"], "ID");
+        // Every repeated ID once, in batch order
+        assert_eq!(error["error"], format!("Duplicate offer IDs: {}, {}", first.ID, second.ID));
+
+        assert_eq!(state.cache.read().unwrap().len(), 0);
+        for offer in [&first, &second, &unique] {
+            assert!(read_offer(&state.db, state.shards, &offer.ID).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn price_stats_take_the_middle_of_the_sorted_prices() {
+        let stats = |prices: &[u16]| {
+            let stats = compute_price_stats(&filtered(&offers_priced(prices)));
+            (stats.minPrice, stats.maxPrice, stats.medianPrice)
+        };
+        assert_eq!(stats(&[]), (None, None, None));
+        assert_eq!(stats(&[70]), (Some(70), Some(70), Some(70.0)));
+        assert_eq!(stats(&[90, 10, 50]), (Some(10), Some(90), Some(50.0)));
+        assert_eq!(stats(&[40, 10, 90, 25]), (Some(10), Some(90), Some(32.5)));
+        // Duplicates of the middle price count as often as they occur
+        assert_eq!(stats(&[30, 30, 10, 30, 99]), (Some(10), Some(99), Some(30.0)));
+        assert_eq!(stats(&[5, 5, 5, 8]), (Some(5), Some(8), Some(5.0)));
+    }
+
+    #[tokio::test]
+    async fn naming_snake_renames_every_key_of_the_search() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        post_offers(&router, &varied_offers(8)).await;
+        let opt_in = ("includeRegionCounts", "true");
+        let camel = search(&router, &[opt_in]).await;
+        let snake = search(&router, &[opt_in, ("naming", "snake")]).await;
+
+        let mut keys: Vec<&str> = snake.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            [
+                "car_type_counts",
+                "free_kilometer_range",
+                "free_kilometer_stats",
+                "offers",
+                "price_ranges",
+                "price_stats",
+                "region_counts",
+                "seats_count",
+                "total_offers",
+                "vollkasko_count",
+            ]
+        );
+        // Nested keys too, the values stay the same
+        assert_eq!(snake["price_stats"]["median_price"], camel["priceStats"]["medianPrice"]);
+        assert_eq!(snake["region_counts"][0]["region_id"], camel["regionCounts"][0]["regionID"]);
+        assert_eq!(snake["free_kilometer_range"], camel["freeKilometerRange"]);
+        assert_eq!(snake["offers"][0]["id"], camel["offers"][0]["ID"]);
+        assert_eq!(snake["offers"][0]["data"], camel["offers"][0]["data"]);
+
+        let (status, error) = search_error(&router, &[("naming", "kebab")]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["field"], "naming");
+    }
+
+    #[test]
+    fn overlap_also_matches_offers_straddling_the_range() {
+        let inside = lasting(10, 2);
+        let straddles_start = lasting(4, 2);
+        let straddles_end = lasting(19, 2);
+        let touches_start = lasting(3, 2);
+        let before = lasting(1, 2);
+        let after = lasting(21, 2);
+        let offers = [
+            inside.clone(),
+            straddles_start.clone(),
+            straddles_end.clone(),
+            touches_start.clone(),
+            before,
+            after,
+        ];
+        let (start, end) = ((5 * DAY).to_string(), (20 * DAY).to_string());
+        let range = |time_match| {
+            [("timeRangeStart", start.as_str()), ("timeRangeEnd", end.as_str()), ("timeMatch", time_match)]
+        };
+
+        assert_eq!(matching(&offers, &range("contained")), [inside.ID]);
+        assert_eq!(
+            matching(&offers, &range("overlap")),
+            [inside.ID, straddles_start.ID, straddles_end.ID, touches_start.ID]
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_get_lists_missing_ids_once_in_request_order() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let stored = varied_offers(3);
+        post_offers(&router, &stored).await;
+        let (gone, never) = (Uuid::new_v4(), Uuid::new_v4());
+        let ids = serde_json::json!({ "ids": [never, stored[0].ID, gone, never, stored[2].ID, stored[0].ID] });
+
+        let response = send(&router, json_request("POST", "/api/offers/batch", &ids)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let batch = body_json(response).await;
+        let mut found: Vec<String> = batch["offers"].as_object().unwrap().keys().cloned().collect();
+        found.sort();
+        let mut expected = [stored[0].ID.to_string(), stored[2].ID.to_string()];
+        expected.sort();
+        assert_eq!(found, expected);
+        assert_eq!(batch["offers"][stored[2].ID.to_string()]["price"], stored[2].price);
+        assert_eq!(batch["missing"], serde_json::json!([never, gone]));
+    }
+
+    #[tokio::test]
+    async fn batch_get_rejects_more_than_max_batch_ids() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[("MAX_BATCH_IDS", "3")]);
+        let ids = |count: usize| serde_json::json!({ "ids": (0..count).map(|_| Uuid::new_v4()).collect::<Vec<_>>() });
+
+        let response = send(&router, json_request("POST", "/api/offers/batch", &ids(3))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&router, json_request("POST", "/api/offers/batch", &ids(4))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error = body_json(response).await;
+        assert_eq!(error["field"], "ids");
+        assert_eq!(error["error"], "At most 3 IDs can be fetched at once");
+    }
+
+    #[tokio::test]
+    async fn explain_counts_the_offers_left_after_each_filter() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let mut offers = varied_offers(60);
+        // Scanned, but of the wrong duration
+        offers.extend([lasting(10, 3), lasting(30, 1)]);
+        post_offers(&router, &offers).await;
+        let filters = [
+            ("minNumberSeats", "4"),
+            ("maxPrice", "300"),
+            ("carType", "small,family"),
+            ("onlyVollkasko", "true"),
+            ("minFreeKilometer", "200"),
+        ];
+        let mut overrides = filters.to_vec();
+        overrides.push(("explain", "true"));
+        let explain = search(&router, &overrides).await["explain"].clone();
+        assert_eq!(explain["scanned"], 62);
+        assert_eq!(explain["timeMatches"], 60);
+
+        let mut remaining: Vec<&Offer> = offers[..60].iter().collect();
+        let mut expected = Vec::new();
+        let mut stage = |filter: &str, keep: fn(&Offer) -> bool| {
+            remaining.retain(|offer| keep(offer));
+            expected.push(serde_json::json!({ "filter": filter, "remaining": remaining.len() }));
+        };
+        stage("seats", |offer| offer.numberSeats >= 4);
+        stage("price", |offer| offer.price < 300);
+        stage("carType", |offer| matches!(offer.carType, CarType::Small | CarType::Family));
+        stage("vollkasko", |offer| offer.hasVollkasko);
+        stage("freeKilometer", |offer| offer.freeKilometers >= 200);
+        assert!(!remaining.is_empty());
+        assert_eq!(explain["filterStages"], serde_json::Value::from(expected));
+        assert_eq!(search(&router, &filters).await["totalOffers"], remaining.len());
+    }
+
+    #[tokio::test]
+    async fn load_shedding_answers_503_over_the_limit_except_for_health() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[("MAX_CONCURRENCY", "1"), ("LOAD_SHED", "1")]);
+        let body = serde_json::to_value([offer()]).unwrap();
+
+        // The upload takes the only slot and waits for the held write lock
+        let guard = state.write_lock.clone().lock_owned().await;
+        let over_the_limit = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let shed = send(&router, request("GET", &search_uri(&[]))).await;
+            let health = send(&router, request("GET", "/health")).await;
+            drop(guard);
+            (shed, health)
+        };
+        let (upload, (shed, health)) =
+            tokio::join!(send(&router, json_request("POST", "/api/offers", &body)), over_the_limit);
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_json(shed).await["error"], "Too many requests in flight, try again later");
+        assert_eq!(health.status(), StatusCode::OK);
+        assert_eq!(upload.status(), StatusCode::OK);
+
+        // The slot is free again
+        assert_eq!(send(&router, request("GET", &search_uri(&[]))).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn free_kilometer_stats_ignore_only_the_free_kilometer_filters() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let offers: Vec<Offer> = [(150, 4), (20, 4), (800, 2), (400, 4)]
+            .into_iter()
+            .map(|(free_kilometers, number_seats)| Offer {
+                freeKilometers: free_kilometers,
+                numberSeats: number_seats,
+                ..offer()
+            })
+            .collect();
+        post_offers(&router, &offers).await;
+
+        let stats = |result: serde_json::Value| result["freeKilometerStats"].clone();
+        assert_eq!(
+            stats(search(&router, &[]).await),
+            serde_json::json!({ "minFreeKilometer": 20, "maxFreeKilometer": 800 })
+        );
+        let filters = [("minNumberSeats", "4"), ("minFreeKilometer", "300"), ("maxFreeKilometer", "500")];
+        let result = search(&router, &filters).await;
+        assert_eq!(result["totalOffers"], 1);
+        assert_eq!(stats(result), serde_json::json!({ "minFreeKilometer": 20, "maxFreeKilometer": 400 }));
+        assert_eq!(
+            stats(search(&router, &[("regionID", "2")]).await),
+            serde_json::json!({ "minFreeKilometer": null, "maxFreeKilometer": null })
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn an_insert_during_a_scan_is_not_seen_by_it() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[]);
+        post_offers(&router, &varied_offers(10)).await;
+        let params = search_params(&[]);
+        let scan = |cache: &OfferCache| -> Vec<Uuid> {
+            query_offers(cache, &state.regions, &params).iter().map(|offer| offer.ID).collect()
+        };
+
+        let inserted = offer();
+        let body = serde_json::to_value([&inserted]).unwrap();
+        let upload = {
+            let cache = state.cache.read().unwrap();
+            let before = scan(&cache);
+            let request = json_request("POST", "/api/offers", &body);
+            let upload = tokio::spawn(tower::ServiceExt::oneshot(router.clone(), request));
+            // The upload reaches the database, then waits for the scan to release the cache
+            for _ in 0..100 {
+                if read_offer(&state.db, state.shards, &inserted.ID).unwrap().is_some() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert!(read_offer(&state.db, state.shards, &inserted.ID).unwrap().is_some());
+            assert_eq!(scan(&cache), before);
+            assert!(!upload.is_finished());
+            upload
+        };
+        assert_eq!(upload.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert!(scan(&state.cache.read().unwrap()).contains(&inserted.ID));
+    }
+
+    fn version_request(query: &str, etag: &header::HeaderValue) -> Request<Body> {
+        let mut request = request("GET", &format!("/api/offers/version{}", query));
+        request.headers_mut().insert(header::IF_NONE_MATCH, etag.clone());
+        request
+    }
+
+    #[tokio::test]
+    async fn an_unchanged_version_is_not_modified() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let response = send(&router, request("GET", "/api/offers/version")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG].clone();
+
+        let response = send(&router, version_request("", &etag)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag);
+        // Waiting without a write in the meantime ends the same way
+        let started = Instant::now();
+        let response = send(&router, version_request("?wait=1", &etag)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn a_waiting_version_request_returns_on_a_write() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let response = send(&router, request("GET", "/api/offers/version")).await;
+        let etag = response.headers()[header::ETAG].clone();
+        let generation = body_json(response).await["generation"].as_u64().unwrap();
+
+        let started = Instant::now();
+        let write_later = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            post_offers(&router, &[offer()]).await;
+        };
+        let (response, ()) = tokio::join!(send(&router, version_request("?wait=30", &etag)), write_later);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[header::ETAG], etag);
+        assert!(body_json(response).await["generation"].as_u64().unwrap() > generation);
+    }
+
+    #[tokio::test]
+    async fn searches_without_a_region_need_allow_global_query() {
+        let in_region = |region_id| Offer {
+            mostSpecificRegionID: region_id,
+            ..offer()
+        };
+        let offers = [in_region(1), in_region(2), in_region(7)];
+        let (no_region, all) = (("regionID", ""), ("regionID", "all"));
+
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        post_offers(&router, &offers).await;
+        let (status, error) = search_error(&router, &[no_region]).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error["fields"], serde_json::json!(["regionID"]));
+        let (status, error) = search_error(&router, &[all]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["error"], "Global queries are disabled, see ALLOW_GLOBAL_QUERY");
+
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[("ALLOW_GLOBAL_QUERY", "1")]);
+        post_offers(&router, &offers).await;
+        for overrides in [no_region, all] {
+            let mut found = result_ids(&search(&router, &[overrides]).await);
+            found.sort();
+            let mut expected = ids(&offers);
+            expected.sort();
+            assert_eq!(found, expected);
+        }
+        // A regionID still narrows the search
+        assert_eq!(result_ids(&search(&router, &[("regionID", "2")]).await), ids(&offers[1..2]));
+    }
+
+    #[tokio::test]
+    async fn changing_data_compression_keeps_the_stored_offers_readable() {
+        let dir = TempDir::new();
+        let (plain, zstd) = (offer(), offer());
+        {
+            let (_, router) = test_app(&dir, &[("DATA_COMPRESSION", "none")]);
+            post_offers(&router, std::slice::from_ref(&plain)).await;
+        }
+        {
+            let (_, router) = test_app(&dir, &[("DATA_COMPRESSION", "zstd")]);
+            post_offers(&router, std::slice::from_ref(&zstd)).await;
+        }
+        // Each setting reads what the other one wrote
+        for compression in ["zstd", "none"] {
+            let (state, router) = test_app(&dir, &[("DATA_COMPRESSION", compression)]);
+            assert_eq!(state.cache.read().unwrap().len(), 2);
+            for offer in [&plain, &zstd] {
+                let response = send(&router, request("GET", &format!("/api/offers/{}", offer.ID))).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let mut stored = body_json(response).await;
+                stored["createdAt"] = serde_json::Value::Null;
+                assert_eq!(stored, serde_json::to_value(offer).unwrap(), "{}", compression);
+            }
+            // The cache decoded them as well
+            for found in search(&router, &[]).await["offers"].as_array().unwrap() {
+                assert_eq!(found["data"], serde_json::to_value(&plain).unwrap()["data"]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cursor_pages_equal_offset_pages() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        // Few distinct prices, so pages split runs of ties
+        let offers: Vec<Offer> = (0..47u16).map(|i| Offer { price: 10 + i % 4, ..offer() }).collect();
+        post_offers(&router, &offers).await;
+
+        for sort_order in ["price-asc", "price-desc"] {
+            let mut by_offset = Vec::new();
+            for page in 1..=5 {
+                let page = page.to_string();
+                let result = search(&router, &[("sortOrder", sort_order), ("page", &page)]).await;
+                by_offset.extend(result_ids(&result));
+            }
+            let mut by_cursor = Vec::new();
+            let first_page = [("sortOrder", sort_order), ("pagination", "cursor"), ("page", "")];
+            let mut result = search(&router, &first_page).await;
+            loop {
+                by_cursor.extend(result_ids(&result));
+                let cursor = match result["nextCursor"].as_str() {
+                    Some(cursor) => cursor.to_string(),
+                    None => break,
+                };
+                result = search(&router, &[("sortOrder", sort_order), ("cursor", &cursor), ("page", "")]).await;
+            }
+            assert_eq!(by_cursor.len(), offers.len());
+            assert_eq!(by_cursor, by_offset, "{}", sort_order);
+        }
+    }
+
+    #[tokio::test]
+    async fn cursors_not_made_by_a_search_are_rejected() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let too_short = URL_SAFE_NO_PAD.encode([1u8; 16]);
+        for cursor in ["not a cursor!", too_short.as_str()] {
+            let (status, error) = search_error(&router, &[("cursor", cursor), ("page", "")]).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{:?}", cursor);
+            assert_eq!(error["field"], "cursor");
+        }
+    }
+
+    #[tokio::test]
+    async fn offer_paths_accept_uppercase_and_simple_ids() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let stored = offer();
+        post_offers(&router, std::slice::from_ref(&stored)).await;
+
+        let id = stored.ID;
+        let simple_upper = id.simple().to_string().to_uppercase();
+        for form in [id.hyphenated().to_string().to_uppercase(), id.simple().to_string(), simple_upper.clone()] {
+            let response = send(&router, request("GET", &format!("/api/offers/{}", form))).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", form);
+            assert_eq!(body_json(response).await["ID"], id.to_string());
+        }
+        let response = send(&router, request("DELETE", &format!("/api/offers/{}", simple_upper))).await;
+        assert!(response.status().is_success());
+        let response = send(&router, request("GET", &format!("/api/offers/{}", id))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn data_prefix_matches_offers_whose_data_starts_with_it() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let with_data = |head: &[u8]| {
+            let mut data = offer().data;
+            data[..head.len()].copy_from_slice(head);
+            Offer { data, ..offer() }
+        };
+        let offers = [with_data(b"car:eu"), with_data(b"car:us"), with_data(b"van:eu")];
+        post_offers(&router, &offers).await;
+        let prefix = |bytes: &[u8]| STANDARD.encode(bytes);
+
+        let cars = prefix(b"car:");
+        let mut found = result_ids(&search(&router, &[("dataPrefix", &cars)]).await);
+        found.sort();
+        let mut expected = ids(&offers[..2]);
+        expected.sort();
+        assert_eq!(found, expected);
+        let van = prefix(b"van:eu");
+        assert_eq!(result_ids(&search(&router, &[("dataPrefix", &van)]).await), ids(&offers[2..]));
+        let bikes = prefix(b"bike");
+        assert!(result_ids(&search(&router, &[("dataPrefix", &bikes)]).await).is_empty());
+
+        let (status, error) = search_error(&router, &[("dataPrefix", "not base64!")]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["field"], "dataPrefix");
+    }
+
+    #[tokio::test]
+    async fn writes_between_stream_chunks_leave_the_streamed_lines_alone() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        // Equal prices, so the lines come in ID order
+        let mut offers: Vec<Offer> = (0..1500).map(|_| offer()).collect();
+        offers.sort_by_key(|offer| offer.ID);
+        post_offers(&router, &offers).await;
+        let uri = search_uri(&[("maxPrice", "1000"), ("stream", "true")]);
+
+        let mut body = send(&router, request("GET", &uri)).await.into_body();
+        let mut streamed = hyper::body::HttpBody::data(&mut body).await.unwrap().unwrap().to_vec();
+        assert!(streamed.iter().filter(|&&byte| byte == b'\n').count() < offers.len());
+        // Before the remaining chunks: a delete, a change out of the search and a new match
+        let (deleted, changed) = (&offers[1200], &offers[1300]);
+        let response = send(&router, request("DELETE", &format!("/api/offers/{}", deleted.ID))).await;
+        assert!(response.status().is_success());
+        let patch = serde_json::json!({ "price": 5000, "data": STANDARD.encode(vec![1; changed.data.len()]) });
+        assert_eq!(send(&router, patch_request(&changed.ID, patch)).await.status(), StatusCode::OK);
+        post_offers(&router, &[offer()]).await;
+        streamed.extend(hyper::body::to_bytes(body).await.unwrap());
+
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&streamed)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let expected: Vec<serde_json::Value> = offers
+            .iter()
+            .map(|offer| serde_json::to_value(FilteredOffer::from(offer).to_search_result()).unwrap())
+            .collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[tokio::test]
+    async fn oversized_ndjson_lines_are_reported_and_skipped() {
+        let dir = TempDir::new();
+        let (state, router) = test_app(&dir, &[]);
+        let (first, last) = (offer(), offer());
+        let line = |offer: &Offer| format!("{}\n", serde_json::to_string(offer).unwrap());
+        // An oversized line split over several chunks, and one cut off by the end of the body
+        let mut chunks = vec![line(&first), "{\"ID\": \"".to_string()];
+        chunks.extend((0..5).map(|_| "x".repeat(MAX_NDJSON_LINE_LEN / 4)));
+        chunks.push(format!("\"}}\n{}", line(&last)));
+        chunks.push("y".repeat(MAX_NDJSON_LINE_LEN + 1));
+        let chunks = stream::iter(chunks.into_iter().map(Ok::<_, std::io::Error>));
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/offers/ndjson")
+            .body(Body::wrap_stream(chunks))
+            .unwrap();
+
+        let response = send(&router, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let error = format!("Line is longer than {} bytes", MAX_NDJSON_LINE_LEN);
+        assert_eq!(
+            body_json(response).await,
+            serde_json::json!({
+                "inserted": 2,
+                "errors": [{ "line": 2, "error": error }, { "line": 4, "error": error }],
+                "omittedErrors": 0,
+            })
+        );
+        assert_eq!(state.cache.read().unwrap().len(), 2);
+        assert!(read_offer(&state.db, state.shards, &last.ID).unwrap().is_some());
+    }
+
+    // Rayon checks offers on its own threads, which the counter doesn't see
+    #[cfg(not(feature = "parallel"))]
+    #[test]
+    fn offers_starting_outside_the_window_are_never_visited() {
+        // Ten offers starting on each of 100 days, in two regions
+        let offers: Vec<Offer> = (0..1000)
+            .map(|i| Offer {
+                mostSpecificRegionID: 1 + i % 2,
+                ..lasting(i64::from(i / 10), 2)
+            })
+            .collect();
+        let cache = cache_of(&offers);
+        let (start, end) = ((40 * DAY).to_string(), (45 * DAY).to_string());
+        let params = search_params(&[("timeRangeStart", start.as_str()), ("timeRangeEnd", end.as_str())]);
+
+        let visited_before = VISITED_OFFERS.with(|visited| visited.get());
+        let found = query_offers(&cache, &RegionTree::default(), &params);
+        let visited = VISITED_OFFERS.with(|visited| visited.get()) - visited_before;
+        // Region 1 has five offers starting on each of days 40 to 43, the only ones lasting
+        // 2 days inside the range, and no other offer is looked at
+        assert_eq!(found.len(), 20);
+        assert_eq!(visited, 20);
+    }
+}
@@ -1,24 +1,345 @@
-use axum::{Router, routing::{get, post, delete}};
-use crate::handlers::{get_offers, create_offers, cleanup_data};
-use std::net::SocketAddr;
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
+    http::{header, HeaderName, HeaderValue, Method},
+    routing::{delete, get, post},
+    Router,
+};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::ServiceBuilder;
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::timeout::TimeoutLayer;
+use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
+use tower_http::LatencyUnit;
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
+use crate::state::{AdminToken, AppState, Limits, ADMIN_TOKEN_HEADER};
+use crate::handlers::{get_offers, create_offers, cleanup_data, get_offer_by_id, update_offer, delete_offer, health, count_offers, head_offers, get_facets, offer_stats, export_offers, import_offers, ingest_ndjson, seed_offers, method_not_allowed, compact_database, get_offers_batch, overloaded, offer_version};
 
+mod cache;
+mod codec;
+mod config;
 mod db;
+mod error;
+mod expiry;
+mod extract;
 mod models;
+mod naming;
+mod openapi;
+mod regions;
+mod seed;
 mod handlers;
+mod state;
+#[cfg(test)]
+mod testutil;
+mod validation;
 
-#[tokio::main]
-async fn main() {
+fn main() {
+    // Log level is controlled by RUST_LOG, request logs are on by default
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    // Read the configuration, failing fast on invalid values
+    let config = config::Config::from_env().unwrap_or_else(|e| {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    });
+
+    // The runtime is built by hand so the worker count comes from the config
+    tracing::info!("Starting runtime with {} worker threads", config.worker_threads);
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.worker_threads)
+        .enable_all()
+        .build()
+        .expect("Failed to build the tokio runtime")
+        .block_on(run(config));
+}
+
+async fn run(config: config::Config) {
     // Initialize the database
-    let db = db::init_db().expect("Failed to initialize database");
+    let db = db::init_db(&config.db_path, &config.db).expect("Failed to initialize database");
+    let shards = db::Shards::new(config.db.shards);
+    tracing::info!(path = %config.db_path, shards = shards.count(), "Opened database");
+
+    // Load every offer into the in-memory cache used by queries
+    let started = std::time::Instant::now();
+    let cache = cache::OfferCache::load(&db, shards).expect("Failed to load offers");
+    tracing::info!(offers = cache.len(), elapsed = ?started.elapsed(), "Loaded offers");
+    // Load the region tree, queries for a region also match its subregions
+    let regions = regions::RegionTree::load(&config.regions_path).unwrap_or_else(|e| {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    });
+    tracing::info!(
+        path = %config.regions_path,
+        root = regions.root_name(),
+        regions = regions.len(),
+        "Loaded region tree"
+    );
+    let (offer_count, region_count) = (cache.len(), regions.len());
+
+    let state = AppState {
+        db: db.clone(),
+        cache: Arc::new(RwLock::new(cache)),
+        regions: Arc::new(regions),
+        write_lock: Arc::new(tokio::sync::Mutex::new(())),
+        shards,
+        facets: Arc::new(cache::FacetCache::default()),
+        write_retry: config.db.write_retry(),
+        admin_token: AdminToken(config.admin_token.as_deref().map(Arc::from)),
+        limits: Limits {
+            max_batch_ids: config.max_batch_ids,
+            allow_global_query: config.allow_global_query,
+        },
+        data_compression: config.data_compression,
+    };
+    if config.admin_token.is_none() {
+        tracing::info!("ADMIN_TOKEN is not set, admin endpoints are disabled");
+    }
+
+    // Periodically drop offers that already ended
+    if config.expiry_interval_secs > 0 {
+        let interval = Duration::from_secs(config.expiry_interval_secs);
+        tokio::spawn(expiry::run(state.clone(), interval));
+        tracing::info!("Expiring ended offers every {:?}", interval);
+    }
 
     // Build our application with some routes
+    let app = app(state, &config);
+
+    // Run it with hyper on the configured address until a shutdown signal arrives
+    let server = axum::Server::bind(&config.bind_addr).serve(app.into_make_service());
+    tracing::info!(
+        addr = %server.local_addr(),
+        offers = offer_count,
+        regions = region_count,
+        workers = config.worker_threads,
+        "Ready"
+    );
+    server
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+
+    // In-flight requests are done, persist the memtables of every column family before exiting
+    tracing::info!("Flushing database");
+    if let Err(e) = db::flush_all(&db) {
+        tracing::error!("Failed to flush database: {}", e);
+    }
+    tracing::info!("Shutdown complete");
+}
+
+// Every route with its layers, over the database and cache in state
+fn app(state: AppState, config: &config::Config) -> Router {
     let app = Router::new()
-        .route("/api/offers", get(get_offers).post(create_offers).delete(cleanup_data))
-        // Add the database to the app's state
-        .with_state(db);
-
-    // Run it with hyper on localhost:8080
-    let addr = SocketAddr::from(([0, 0, 0, 0], 80));
-    println!("Listening on {}", addr);
-    axum::Server::bind(&addr).serve(app.into_make_service()).await.unwrap();
-}
\ No newline at end of file
+        .route(
+            "/api/offers",
+            get(get_offers)
+                // HEAD only computes the count instead of a stripped GET body
+                .head(head_offers)
+                // Only uploads get the raised body limit
+                .merge(post(create_offers).layer(DefaultBodyLimit::max(config.body_limit)))
+                // Unsupported methods get a 405 with an Allow header. Only one of the merged
+                // method routers of a path may have a fallback, the later DELETE has none.
+                .fallback(method_not_allowed),
+        )
+        .route("/api/offers/count", get(count_offers).fallback(method_not_allowed))
+        .route("/api/offers/batch", post(get_offers_batch).fallback(method_not_allowed))
+        .route("/api/offers/facets", get(get_facets).fallback(method_not_allowed))
+        .route("/api/offers/stats", get(offer_stats).fallback(method_not_allowed))
+        .route("/api/offers/export", get(export_offers).fallback(method_not_allowed))
+        .route("/api-docs/openapi.json", get(openapi::openapi_json).fallback(method_not_allowed))
+        .route(
+            "/api/offers/:id",
+            get(get_offer_by_id)
+                .patch(update_offer)
+                .delete(delete_offer)
+                .fallback(method_not_allowed),
+        )
+        // Only covers the routes above. Scans run synchronously once started, so this bounds
+        // the time spent waiting, on the body or the write lock. A write that already started
+        // still completes after the timeout, see state::run_write.
+        .layer(TimeoutLayer::new(Duration::from_millis(config.request_timeout_ms)))
+        // Cleanup and import rewrite and compact the whole database, which may take longer.
+        // Adding a route to an existing path merges it with the methods already there.
+        .route("/api/offers", delete(cleanup_data))
+        .route(
+            "/api/offers/import",
+            // Snapshots of a whole dataset are far bigger than an upload
+            post(import_offers)
+                .layer(DefaultBodyLimit::max(config.import_body_limit))
+                .fallback(method_not_allowed),
+        )
+        // Streamed, so neither the body limit nor the timeout apply
+        .route("/api/offers/ndjson", post(ingest_ndjson).fallback(method_not_allowed))
+        .route("/api/offers/seed", post(seed_offers).fallback(method_not_allowed))
+        // Compacting a big database takes a while
+        .route("/api/admin/compact", post(compact_database).fallback(method_not_allowed));
+
+    // Bound the requests in flight so bursts of full scans can't exhaust memory. The limit is
+    // shared by every route above, axum layers each route separately.
+    let app = match config.max_concurrency {
+        0 => app,
+        max if config.load_shed => app.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(overloaded))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::new(max)),
+        ),
+        max => app.layer(GlobalConcurrencyLimitLayer::new(max)),
+    };
+
+    app
+        // Outside the concurrency limit so probes still answer under load
+        .route("/health", get(health).fallback(method_not_allowed))
+        // Long-polls mostly sleep, they neither time out nor take a concurrency slot
+        .route("/api/offers/version", get(offer_version).fallback(method_not_allowed))
+        // Let browser frontends on other origins call the API, preflights included
+        .layer(cors_layer(config.cors_allow_origin.clone()))
+        // Compress every response when the client sends Accept-Encoding
+        .layer(CompressionLayer::new())
+        // Log method, path, status and latency of every request
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .on_response(
+                    DefaultOnResponse::new()
+                        .level(Level::INFO)
+                        .latency_unit(LatencyUnit::Millis),
+                ),
+        )
+        // Add the database and cache to the app's state
+        .with_state(state)
+}
+
+// Any origin unless CORS_ALLOW_ORIGIN lists some
+fn cors_layer(allow_origin: Option<Vec<HeaderValue>>) -> CorsLayer {
+    let allow_origin = match allow_origin {
+        Some(origins) => AllowOrigin::list(origins),
+        None => AllowOrigin::any(),
+    };
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::HEAD, Method::POST, Method::PATCH, Method::DELETE])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::IF_NONE_MATCH,
+            HeaderName::from_static(ADMIN_TOKEN_HEADER),
+        ])
+        .expose_headers([header::ETAG])
+}
+
+// Resolves on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("Shutdown signal received, draining connections");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{body_bytes, body_json, request, send, test_app, TempDir};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+
+    #[tokio::test]
+    async fn responses_are_gzipped_when_the_client_accepts_it() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let mut gzip = request("GET", "/api-docs/openapi.json");
+        gzip.headers_mut().insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let response = send(&router, gzip).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        // The gzip magic number
+        assert_eq!(body_bytes(response).await[..2], [0x1f, 0x8b]);
+
+        let response = send(&router, request("GET", "/api-docs/openapi.json")).await;
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::builder()
+            .method("OPTIONS")
+            .uri("/api/offers")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn preflights_allow_any_origin_by_default() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let response = send(&router, preflight("https://example.com")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_METHODS].to_str().unwrap().contains("POST"));
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap().contains("content-type"));
+    }
+
+    #[tokio::test]
+    async fn preflights_only_allow_the_configured_origins() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[("CORS_ALLOW_ORIGIN", "https://a.example, https://b.example")]);
+        let response = send(&router, preflight("https://b.example")).await;
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://b.example");
+        let response = send(&router, preflight("https://c.example")).await;
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn unsupported_methods_list_every_method_of_the_path() {
+        let dir = TempDir::new();
+        let (_, router) = test_app(&dir, &[]);
+        let allowed = |response: &axum::response::Response| {
+            let mut methods: Vec<String> = response.headers()[header::ALLOW]
+                .to_str()
+                .unwrap()
+                .split(',')
+                .map(|method| method.trim().to_string())
+                .collect();
+            methods.sort();
+            methods
+        };
+        // DELETE is merged into the path by a later route() call
+        let response = send(&router, request("PUT", "/api/offers")).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allowed(&response), ["DELETE", "GET", "HEAD", "POST"]);
+        assert_eq!(body_json(response).await["error"], "Method not allowed");
+
+        let id = uuid::Uuid::new_v4();
+        let response = send(&router, request("POST", &format!("/api/offers/{}", id))).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allowed(&response), ["DELETE", "GET", "HEAD", "PATCH"]);
+
+        let response = send(&router, request("GET", "/api/admin/compact")).await;
+        assert_eq!(allowed(&response), ["POST"]);
+    }
+}
@@ -2,37 +2,339 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct Offer {
     pub ID: Uuid,
     #[serde(with = "base64_standard")]
+    #[schema(value_type = String, format = Byte)]
     pub data: Vec<u8>,
     pub mostSpecificRegionID: i32,
     pub startDate: i64,
     pub endDate: i64,
     pub numberSeats: u8,
     pub price: u16,
-    pub carType: String,
+    pub carType: CarType,
     pub hasVollkasko: bool,
     pub freeKilometers: u16,
+    /// Milliseconds since the epoch of the first insert, set on insert when left out
+    #[serde(default)]
+    pub createdAt: Option<i64>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// Query parameters of GET /api/offers, only used for the OpenAPI spec since
+// get_offers parses them by hand to report missing and invalid values
+#[allow(dead_code)]
+#[derive(Deserialize, Clone, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OfferQuery {
+    /// A region ID, or all or left out to search every region when ALLOW_GLOBAL_QUERY=1
+    pub regionID: Option<String>,
+    pub timeRangeStart: i64,
+    pub timeRangeEnd: i64,
+    /// contained keeps offers starting and ending inside the time range, overlap any offer sharing time
+    /// with it. contained stays the default so searches without timeMatch match as before, clients
+    /// wanting overlapping offers have to pass timeMatch=overlap.
+    pub timeMatch: Option<String>,
+    /// Required unless minNumberDays or maxNumberDays is given, can't be combined with them
+    pub numberDays: Option<i32>,
+    /// Inclusive lower bound on the whole days between startDate and endDate, 1 by default
+    pub minNumberDays: Option<i32>,
+    /// Inclusive upper bound on the whole days between startDate and endDate, unbounded by default
+    pub maxNumberDays: Option<i32>,
+    /// price-asc, price-desc, kilometers-asc, kilometers-desc, start-date-asc, start-date-desc or created-desc
+    pub sortOrder: String,
+    /// Required with the default offset pagination
+    pub page: Option<u32>,
+    pub pageSize: u32,
+    /// offset, the default, or cursor to page with the nextCursor of each result instead of page
+    pub pagination: Option<String>,
+    /// nextCursor of the previous page, implies pagination=cursor. Only valid for the same search.
+    pub cursor: Option<String>,
+    pub priceRangeWidth: u32,
+    /// Comma separated, strictly increasing boundaries of fixed price bands used instead of priceRangeWidth
+    pub priceBuckets: Option<String>,
+    pub minFreeKilometerWidth: u32,
+    pub minNumberSeats: Option<u8>,
+    /// Exact seat count, can't be combined with minNumberSeats
+    pub numberSeats: Option<u8>,
+    pub minPrice: Option<u16>,
+    pub maxPrice: Option<u16>,
+    /// Comma separated or repeated to select several types
+    pub carType: Option<Vec<CarType>>,
+    pub onlyVollkasko: Option<bool>,
+    pub minFreeKilometer: Option<u16>,
+    pub maxFreeKilometer: Option<u16>,
+    /// Base64 bytes the data of an offer has to start with, e.g. to find duplicates
+    pub dataPrefix: Option<String>,
+    /// Defaults to true, false leaves every facet empty
+    pub includeAggregations: Option<bool>,
+    /// Adds the regionCounts facet, off by default
+    pub includeRegionCounts: Option<bool>,
+    /// Streams every match as NDJSON instead, ignoring page, pageSize and the facets
+    pub stream: Option<bool>,
+    /// camel, the default, or snake for snake_case response keys
+    pub naming: Option<String>,
+    /// Adds an explain block with scan counts and timings to the result
+    pub explain: Option<bool>,
+}
+
+// Body of POST /api/offers, either {"offers": [...]} or a bare [...]
+#[derive(Clone, Debug)]
+pub enum OffersPayload {
+    Wrapped { offers: Vec<Offer> },
+    Bare(Vec<Offer>),
+}
+
+impl OffersPayload {
+    pub fn into_offers(self) -> Vec<Offer> {
+        match self {
+            OffersPayload::Wrapped { offers } => offers,
+            OffersPayload::Bare(offers) => offers,
+        }
+    }
+}
+
+// By hand instead of untagged, which buffers the body and reports a bad offer only as
+// "did not match any variant", losing which offer and field was wrong
+impl<'de> Deserialize<'de> for OffersPayload {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PayloadVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for PayloadVisitor {
+            type Value = OffersPayload;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an array of offers or an object with an offers array")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut offers = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(offer) = seq.next_element::<Offer>()? {
+                    offers.push(offer);
+                }
+                Ok(OffersPayload::Bare(offers))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut offers = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "offers" if offers.is_some() => return Err(serde::de::Error::duplicate_field("offers")),
+                        "offers" => offers = Some(map.next_value::<Vec<Offer>>()?),
+                        _ => {
+                            map.next_value::<serde::de::IgnoredAny>()?;
+                        }
+                    }
+                }
+                match offers {
+                    Some(offers) => Ok(OffersPayload::Wrapped { offers }),
+                    None => Err(serde::de::Error::missing_field("offers")),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(PayloadVisitor)
+    }
+}
+
+// Body of POST /api/offers/batch
+#[derive(Deserialize, Clone, Debug, ToSchema)]
+pub struct OfferIds {
+    pub ids: Vec<Uuid>,
+}
+
+// Result of POST /api/offers/batch, every requested ID is either a key of offers or in missing
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct OfferBatch {
+    pub offers: BTreeMap<Uuid, Offer>,
+    pub missing: Vec<Uuid>,
+}
+
+// Partial update of an offer, fields left out keep their current value
+#[derive(Deserialize, Clone, Debug, Default, ToSchema)]
+pub struct OfferPatch {
+    #[serde(default, deserialize_with = "base64_standard::deserialize_option")]
+    #[schema(value_type = Option<String>, format = Byte)]
+    pub data: Option<Vec<u8>>,
+    pub mostSpecificRegionID: Option<i32>,
+    pub startDate: Option<i64>,
+    pub endDate: Option<i64>,
+    pub numberSeats: Option<u8>,
+    pub price: Option<u16>,
+    pub carType: Option<CarType>,
+    pub hasVollkasko: Option<bool>,
+    pub freeKilometers: Option<u16>,
+}
+
+impl OfferPatch {
+    pub fn apply(self, offer: &mut Offer) {
+        if let Some(data) = self.data {
+            offer.data = data;
+        }
+        if let Some(region_id) = self.mostSpecificRegionID {
+            offer.mostSpecificRegionID = region_id;
+        }
+        if let Some(start_date) = self.startDate {
+            offer.startDate = start_date;
+        }
+        if let Some(end_date) = self.endDate {
+            offer.endDate = end_date;
+        }
+        if let Some(number_seats) = self.numberSeats {
+            offer.numberSeats = number_seats;
+        }
+        if let Some(price) = self.price {
+            offer.price = price;
+        }
+        if let Some(car_type) = self.carType {
+            offer.carType = car_type;
+        }
+        if let Some(has_vollkasko) = self.hasVollkasko {
+            offer.hasVollkasko = has_vollkasko;
+        }
+        if let Some(free_kilometers) = self.freeKilometers {
+            offer.freeKilometers = free_kilometers;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CarType {
+    Small,
+    Sports,
+    Luxury,
+    Family,
+}
+
+impl FromStr for CarType {
+    type Err = String;
+
+    // Case sensitive, matching the serde representation
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "small" => Ok(CarType::Small),
+            "sports" => Ok(CarType::Sports),
+            "luxury" => Ok(CarType::Luxury),
+            "family" => Ok(CarType::Family),
+            other => Err(format!("Unknown car type {}", other)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    #[default]
+    PriceAsc,
+    PriceDesc,
+    KilometersAsc,
+    KilometersDesc,
+    // Earliest available cars first
+    StartDateAsc,
+    StartDateDesc,
+    // Most recently created first
+    CreatedDesc,
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "price-asc" => Ok(SortOrder::PriceAsc),
+            "price-desc" => Ok(SortOrder::PriceDesc),
+            "kilometers-asc" => Ok(SortOrder::KilometersAsc),
+            "kilometers-desc" => Ok(SortOrder::KilometersDesc),
+            "start-date-asc" => Ok(SortOrder::StartDateAsc),
+            "start-date-desc" => Ok(SortOrder::StartDateDesc),
+            "created-desc" => Ok(SortOrder::CreatedDesc),
+            other => Err(format!("Unknown sort order {}", other)),
+        }
+    }
+}
+
+// Internal view of an offer used for filtering, sorting and aggregation.
+// Attributes are copied next to each other, the data blob is only borrowed.
+// The time range is matched on the Offer before, so endDate isn't needed.
+#[derive(Clone, Copy, Debug)]
+pub struct FilteredOffer<'a> {
+    pub ID: Uuid,
+    pub mostSpecificRegionID: i32,
+    pub startDate: i64,
+    pub numberSeats: u8,
+    pub price: u16,
+    pub carType: CarType,
+    pub hasVollkasko: bool,
+    pub freeKilometers: u16,
+    // 0 for offers stored before createdAt existed
+    pub createdAt: i64,
+    pub data: &'a [u8],
+}
+
+impl<'a> From<&'a Offer> for FilteredOffer<'a> {
+    fn from(offer: &'a Offer) -> Self {
+        FilteredOffer {
+            ID: offer.ID,
+            mostSpecificRegionID: offer.mostSpecificRegionID,
+            startDate: offer.startDate,
+            numberSeats: offer.numberSeats,
+            price: offer.price,
+            carType: offer.carType,
+            hasVollkasko: offer.hasVollkasko,
+            freeKilometers: offer.freeKilometers,
+            createdAt: offer.createdAt.unwrap_or(0),
+            data: &offer.data,
+        }
+    }
+}
+
+// Data blobs copied by to_search_result on the current thread, counted for the tests
+#[cfg(test)]
+thread_local! {
+    pub static COPIED_DATA: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+impl FilteredOffer<'_> {
+    // Projection to the wire shape, the only place the data blob is copied
+    pub fn to_search_result(self) -> SearchResultOffer {
+        #[cfg(test)]
+        COPIED_DATA.with(|copied| copied.set(copied.get() + 1));
+        SearchResultOffer {
+            ID: self.ID,
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct SearchResultOffer {
     pub ID: Uuid,
     #[serde(with = "base64_standard")]
+    #[schema(value_type = String, format = Byte)]
     pub data: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct PriceRange {
-    pub start: u16,
-    pub end: u16,
+    pub start: u32,
+    pub end: u32,
     pub count: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// Computed over the same offers as priceRanges, all None when there are none.
+// With an even count the median is the mean of the two middle prices.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+pub struct PriceStats {
+    pub minPrice: Option<u16>,
+    pub maxPrice: Option<u16>,
+    pub medianPrice: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
 pub struct CarTypeCount {
     pub small: u32,
     pub sports: u32,
@@ -40,43 +342,172 @@ pub struct CarTypeCount {
     pub family: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
 pub struct VollkaskoCount {
     pub trueCount: u32,
     pub falseCount: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct SeatsCount {
     pub numberSeats: u8,
     pub count: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct FreeKilometerRange {
-    pub start: u16,
-    pub end: u16,
+    pub start: u32,
+    pub end: u32,
     pub count: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// Computed over the same offers as freeKilometerRange, so ignoring minFreeKilometer and
+// maxFreeKilometer, both None when there are none. Meant as the bounds of a slider.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+pub struct FreeKilometerStats {
+    pub minFreeKilometer: Option<u16>,
+    pub maxFreeKilometer: Option<u16>,
+}
+
+// Matching offers of one region, the most specific region of the offers
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct RegionCount {
+    pub regionID: i32,
+    pub count: u32,
+}
+
+// The aggregation blocks of a search, also served alone by GET /api/offers/facets.
+// regionCounts is only computed and sent with includeRegionCounts=true.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+pub struct Facets {
+    pub priceRanges: Vec<PriceRange>,
+    pub priceStats: PriceStats,
+    pub carTypeCounts: CarTypeCount,
+    pub seatsCount: Vec<SeatsCount>,
+    pub freeKilometerRange: Vec<FreeKilometerRange>,
+    pub freeKilometerStats: FreeKilometerStats,
+    pub vollkaskoCount: VollkaskoCount,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regionCounts: Option<Vec<RegionCount>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct SearchResult {
     pub offers: Vec<SearchResultOffer>,
+    pub totalOffers: u32,
+    /// With cursor pagination, the cursor of the next page unless this is the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nextCursor: Option<String>,
     pub priceRanges: Vec<PriceRange>,
+    pub priceStats: PriceStats,
     pub carTypeCounts: CarTypeCount,
     pub seatsCount: Vec<SeatsCount>,
     pub freeKilometerRange: Vec<FreeKilometerRange>,
+    pub freeKilometerStats: FreeKilometerStats,
     pub vollkaskoCount: VollkaskoCount,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regionCounts: Option<Vec<RegionCount>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explain: Option<QueryExplain>,
+}
+
+// Diagnostics of a search with explain=true. The filters run one after the other, so a
+// stage with far fewer remaining offers than the one before is a selective filter.
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct QueryExplain {
+    /// Offers in the searched regions whose startDate could match
+    pub scanned: u32,
+    /// Of those, offers matching the time range, duration and dataPrefix
+    pub timeMatches: u32,
+    pub filterStages: Vec<FilterStage>,
+    pub scanMs: f64,
+    pub sortMs: f64,
+    /// Includes the facet cache lookup, near zero on a hit
+    pub aggregateMs: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct FilterStage {
+    pub filter: String,
+    pub remaining: u32,
+}
+
+// Result of GET /api/offers/version, the ETag holds the generation as well
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct OfferVersion {
+    pub generation: u64,
+}
+
+// Summary of the whole dataset returned by GET /api/offers/stats, ignoring any filter.
+// The price fields are None while the store is empty.
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct OfferStats {
+    pub totalOffers: u32,
+    pub minPrice: Option<u16>,
+    pub maxPrice: Option<u16>,
+    pub avgPrice: Option<f64>,
+    pub distinctRegions: u32,
+    pub carTypeCounts: CarTypeCount,
+}
+
+// Result of POST /api/offers?validate=true, nothing is written
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ValidationReport {
+    pub valid: u32,
+    pub errors: Vec<OfferValidationError>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct OfferValidationError {
+    pub ID: Uuid,
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+// Result of POST /api/offers/ndjson, bad lines are skipped and reported
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct NdjsonReport {
+    pub inserted: u32,
+    /// The first bad lines, the rest are only counted in omittedErrors
+    pub errors: Vec<NdjsonLineError>,
+    pub omittedErrors: u32,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct NdjsonLineError {
+    /// 1-based line number in the request body
+    pub line: u32,
+    pub error: String,
+}
+
+// Result of POST /api/admin/compact
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct CompactionReport {
+    pub elapsedMs: u64,
+    pub columnFamilies: Vec<ColumnFamilyCompaction>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ColumnFamilyCompaction {
+    pub name: String,
+    pub elapsedMs: u64,
+}
+
+// Base64 in human readable formats (the JSON API), raw bytes otherwise (storage).
+// STANDARD is the padded alphabet the deprecated base64::encode/decode used.
 mod base64_standard {
+    use base64::{engine::general_purpose::STANDARD, Engine};
     use serde::{Deserialize, Deserializer, Serializer};
 
     pub fn serialize<S>(bytes: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let encoded = base64::encode(bytes);
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(bytes);
+        }
+        let encoded = STANDARD.encode(bytes);
         serializer.serialize_str(&encoded)
     }
 
@@ -84,7 +515,108 @@ mod base64_standard {
     where
         D: Deserializer<'de>,
     {
+        if !deserializer.is_human_readable() {
+            return Vec::<u8>::deserialize(deserializer);
+        }
         let s = String::deserialize(deserializer)?;
-        base64::decode(&s).map_err(serde::de::Error::custom)
+        STANDARD.decode(&s).map_err(serde::de::Error::custom)
+    }
+
+    pub fn deserialize_option<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| STANDARD.decode(&s).map_err(serde::de::Error::custom))
+            .transpose()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::offer;
+
+    #[test]
+    fn filtered_offer_copies_the_attributes_and_borrows_the_data() {
+        let offer = Offer {
+            createdAt: Some(42),
+            ..offer()
+        };
+        let filtered = FilteredOffer::from(&offer);
+        assert_eq!(filtered.ID, offer.ID);
+        assert_eq!(filtered.mostSpecificRegionID, offer.mostSpecificRegionID);
+        assert_eq!(filtered.startDate, offer.startDate);
+        assert_eq!(filtered.numberSeats, offer.numberSeats);
+        assert_eq!(filtered.price, offer.price);
+        assert_eq!(filtered.carType, offer.carType);
+        assert_eq!(filtered.hasVollkasko, offer.hasVollkasko);
+        assert_eq!(filtered.freeKilometers, offer.freeKilometers);
+        assert_eq!(filtered.createdAt, 42);
+        assert!(std::ptr::eq(filtered.data, offer.data.as_slice()));
+
+        let result = filtered.to_search_result();
+        assert_eq!(result.ID, offer.ID);
+        assert_eq!(result.data, offer.data);
+    }
+
+    #[test]
+    fn filtered_offer_without_created_at_uses_zero() {
+        assert_eq!(FilteredOffer::from(&offer()).createdAt, 0);
+    }
+
+    #[test]
+    fn car_types_use_their_lowercase_names() {
+        for (car_type, name) in [
+            (CarType::Small, "small"),
+            (CarType::Sports, "sports"),
+            (CarType::Luxury, "luxury"),
+            (CarType::Family, "family"),
+        ] {
+            assert_eq!(serde_json::to_value(car_type).unwrap(), name);
+            assert_eq!(serde_json::from_value::<CarType>(name.into()).unwrap(), car_type);
+            assert_eq!(name.parse::<CarType>().unwrap(), car_type);
+        }
+        for unknown in ["Small", "van", ""] {
+            assert!(serde_json::from_value::<CarType>(unknown.into()).is_err());
+            assert!(unknown.parse::<CarType>().is_err());
+        }
+    }
+
+    #[test]
+    fn offers_payload_accepts_bare_and_wrapped_lists() {
+        let list = [offer(), offer()];
+        let expected: Vec<Uuid> = list.iter().map(|offer| offer.ID).collect();
+        let ids = |payload: OffersPayload| -> Vec<Uuid> { payload.into_offers().iter().map(|offer| offer.ID).collect() };
+        let offers = serde_json::to_value(&list).unwrap();
+
+        let bare: OffersPayload = serde_json::from_value(offers.clone()).unwrap();
+        assert!(matches!(bare, OffersPayload::Bare(_)));
+        assert_eq!(ids(bare), expected);
+        // Other keys next to offers are ignored
+        let wrapped: OffersPayload = serde_json::from_value(serde_json::json!({ "offers": offers, "source": "test" })).unwrap();
+        assert!(matches!(wrapped, OffersPayload::Wrapped { .. }));
+        assert_eq!(ids(wrapped), expected);
+
+        assert!(serde_json::from_value::<OffersPayload>(serde_json::json!({ "items": [] })).is_err());
+        assert!(serde_json::from_value::<OffersPayload>(serde_json::json!(1)).is_err());
+    }
+
+    #[test]
+    fn data_round_trips_as_padded_standard_base64() {
+        let offer = Offer {
+            data: (0..=255).collect(),
+            ..offer()
+        };
+        let json = serde_json::to_value(&offer).unwrap();
+        let encoded = json["data"].as_str().unwrap().to_string();
+        // 256 bytes need padding, and the high bytes use the + and / of the standard alphabet
+        assert!(encoded.ends_with('=') && encoded.contains('+') && encoded.contains('/'));
+        assert_eq!(serde_json::from_value::<Offer>(json.clone()).unwrap().data, offer.data);
+
+        let patch: OfferPatch = serde_json::from_value(serde_json::json!({ "data": encoded })).unwrap();
+        assert_eq!(patch.data.unwrap(), offer.data);
+        let mut url_safe = json;
+        url_safe["data"] = encoded.replace('+', "-").replace('/', "_").into();
+        assert!(serde_json::from_value::<Offer>(url_safe).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use axum::http::{header, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use hyper::body::HttpBody;
+use axum::Json;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use std::str::FromStr;
+
+// Key style of JSON responses, ?naming=snake for clients that prefer snake_case.
+// The models stay camelCase as the spec requires, snake_case keys are derived from them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Naming {
+    #[default]
+    Camel,
+    Snake,
+}
+
+impl FromStr for Naming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "camel" => Ok(Naming::Camel),
+            "snake" => Ok(Naming::Snake),
+            other => Err(format!("Unknown naming {}", other)),
+        }
+    }
+}
+
+// JSON response with the keys in the requested style
+pub fn json<T: Serialize>(naming: Naming, value: T) -> Response {
+    let mut response = match naming {
+        Naming::Camel => Json(value).into_response(),
+        Naming::Snake => Json(SnakeCase(value)).into_response(),
+    };
+    // The compression layer hides the size of the buffered body, so without the header hyper
+    // sends even uncompressed responses chunked. Compressing drops the header again.
+    if let Some(len) = response.body().size_hint().exact() {
+        response.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+    }
+    response
+}
+
+// Serializes the value with every object key, nested ones included, in snake_case
+pub struct SnakeCase<T>(pub T);
+
+impl<T: Serialize> Serialize for SnakeCase<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = serde_json::to_value(&self.0).map_err(serde::ser::Error::custom)?;
+        snake_case_keys(value).serialize(serializer)
+    }
+}
+
+fn snake_case_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| (to_snake_case(&key), snake_case_keys(value)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(snake_case_keys).collect()),
+        other => other,
+    }
+}
+
+// An underscore goes before every uppercase letter following a lowercase one or a digit,
+// so priceRanges becomes price_ranges and regionID region_id
+fn to_snake_case(key: &str) -> String {
+    let mut snake = String::with_capacity(key.len() + 4);
+    let mut previous: Option<char> = None;
+    for c in key.chars() {
+        if c.is_ascii_uppercase() && previous.is_some_and(|p| p.is_ascii_lowercase() || p.is_ascii_digit()) {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+        previous = Some(c);
+    }
+    snake
+}
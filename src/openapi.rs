@@ -0,0 +1,126 @@
+use crate::error::ErrorBody;
+use crate::handlers;
+use crate::models::*;
+use axum::Json;
+use utoipa::OpenApi;
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        handlers::get_offers,
+        handlers::count_offers,
+        handlers::head_offers,
+        handlers::get_facets,
+        handlers::offer_stats,
+        handlers::offer_version,
+        handlers::create_offers,
+        handlers::cleanup_data,
+        handlers::export_offers,
+        handlers::import_offers,
+        handlers::ingest_ndjson,
+        handlers::seed_offers,
+        handlers::get_offers_batch,
+        handlers::get_offer_by_id,
+        handlers::update_offer,
+        handlers::delete_offer,
+        handlers::health,
+        handlers::compact_database,
+    ),
+    components(schemas(
+        Offer,
+        OfferPatch,
+        OfferIds,
+        OfferBatch,
+        CarType,
+        SearchResult,
+        QueryExplain,
+        FilterStage,
+        Facets,
+        SearchResultOffer,
+        PriceRange,
+        PriceStats,
+        CarTypeCount,
+        SeatsCount,
+        FreeKilometerRange,
+        FreeKilometerStats,
+        VollkaskoCount,
+        RegionCount,
+        OfferStats,
+        OfferVersion,
+        ValidationReport,
+        OfferValidationError,
+        NdjsonReport,
+        NdjsonLineError,
+        CompactionReport,
+        ColumnFamilyCompaction,
+        ErrorBody,
+    ))
+)]
+pub struct ApiDoc;
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn spec() -> Value {
+        serde_json::to_value(ApiDoc::openapi()).unwrap()
+    }
+
+    #[test]
+    fn lists_every_route() {
+        let spec = spec();
+        let routes = [
+            ("/api/offers", "get"),
+            ("/api/offers", "head"),
+            ("/api/offers", "post"),
+            ("/api/offers", "delete"),
+            ("/api/offers/count", "get"),
+            ("/api/offers/batch", "post"),
+            ("/api/offers/facets", "get"),
+            ("/api/offers/stats", "get"),
+            ("/api/offers/version", "get"),
+            ("/api/offers/export", "get"),
+            ("/api/offers/import", "post"),
+            ("/api/offers/ndjson", "post"),
+            ("/api/offers/seed", "post"),
+            ("/api/offers/{id}", "get"),
+            ("/api/offers/{id}", "patch"),
+            ("/api/offers/{id}", "delete"),
+            ("/api/admin/compact", "post"),
+            ("/health", "get"),
+        ];
+        for (path, method) in routes {
+            assert!(spec["paths"][path][method].is_object(), "{} {} is missing", method, path);
+        }
+    }
+
+    #[test]
+    fn documents_the_search_parameters() {
+        let spec = spec();
+        let params = spec["paths"]["/api/offers"]["get"]["parameters"].as_array().unwrap();
+        let param = |name: &str| params.iter().find(|param| param["name"] == name).unwrap();
+
+        let required = [
+            "timeRangeStart",
+            "timeRangeEnd",
+            "sortOrder",
+            "pageSize",
+            "priceRangeWidth",
+            "minFreeKilometerWidth",
+        ];
+        for name in required {
+            assert_eq!(param(name)["required"], true, "{}", name);
+        }
+        for name in ["regionID", "numberDays", "page", "carType", "dataPrefix"] {
+            assert_ne!(param(name)["required"], true, "{}", name);
+        }
+        // Field doc comments end up as descriptions
+        assert!(param("sortOrder")["description"].as_str().unwrap().contains("price-asc"));
+        assert!(param("timeMatch")["description"].as_str().unwrap().contains("contained stays the default"));
+    }
+}
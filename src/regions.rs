@@ -0,0 +1,115 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::Arc;
+
+pub type SharedRegions = Arc<RegionTree>;
+
+// Node of the region tree file, every node may have nested subregions
+#[derive(Deserialize, Clone, Debug)]
+pub struct Region {
+    pub id: i32,
+    pub name: String,
+    #[serde(default)]
+    pub subregions: Vec<Region>,
+}
+
+// Descendant lookup built once from the region tree at startup
+#[derive(Default, Debug)]
+pub struct RegionTree {
+    root_name: String,
+    descendants: HashMap<i32, HashSet<i32>>,
+    empty: HashSet<i32>,
+}
+
+impl RegionTree {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read region tree {}: {}", path, e))?;
+        let root: Region = serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid region tree {}: {}", path, e))?;
+        Ok(Self::from_root(&root))
+    }
+
+    pub fn from_root(root: &Region) -> Self {
+        let mut tree = RegionTree {
+            root_name: root.name.clone(),
+            ..RegionTree::default()
+        };
+        tree.collect_descendants(root);
+        tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.descendants.len()
+    }
+
+    // Name of the top region, shown in the startup log
+    pub fn root_name(&self) -> &str {
+        &self.root_name
+    }
+
+    // All regions strictly below region_id, empty for leaves and unknown regions
+    pub fn descendants(&self, region_id: i32) -> &HashSet<i32> {
+        self.descendants.get(&region_id).unwrap_or(&self.empty)
+    }
+
+    // Fills the map for the whole subtree and returns the ids below region
+    fn collect_descendants(&mut self, region: &Region) -> HashSet<i32> {
+        let mut below = HashSet::new();
+        for subregion in &region.subregions {
+            below.insert(subregion.id);
+            below.extend(self.collect_descendants(subregion));
+        }
+        self.descendants.insert(region.id, below.clone());
+        below
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(id: i32, subregions: Vec<Region>) -> Region {
+        Region {
+            id,
+            name: format!("Region {}", id),
+            subregions,
+        }
+    }
+
+    // 1 with the subregions 2 and 4, and 3 below 2
+    fn sample_tree() -> RegionTree {
+        RegionTree::from_root(&region(1, vec![region(2, vec![region(3, vec![])]), region(4, vec![])]))
+    }
+
+    #[test]
+    fn descendants_cover_the_whole_subtree() {
+        let tree = sample_tree();
+        assert_eq!(tree.len(), 4);
+        assert_eq!(tree.root_name(), "Region 1");
+        assert_eq!(*tree.descendants(1), HashSet::from([2, 3, 4]));
+        assert_eq!(*tree.descendants(2), HashSet::from([3]));
+        assert!(tree.descendants(3).is_empty());
+        assert!(tree.descendants(99).is_empty());
+    }
+
+    #[test]
+    fn load_counts_every_region_of_the_file() {
+        let dir = crate::testutil::TempDir::new();
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = format!("{}/regions.json", dir.path());
+        let tree = r#"{"id": 0, "name": "Europe", "subregions": [
+            {"id": 1, "name": "Germany", "subregions": [{"id": 2, "name": "Bavaria"}]},
+            {"id": 3, "name": "France"}
+        ]}"#;
+        fs::write(&path, tree).unwrap();
+
+        // The numbers of the startup log
+        let tree = RegionTree::load(&path).unwrap();
+        assert_eq!((tree.root_name(), tree.len()), ("Europe", 4));
+        fs::write(&path, "[]").unwrap();
+        assert!(RegionTree::load(&path).unwrap_err().starts_with("Invalid region tree"));
+        assert!(RegionTree::load(&format!("{}/missing.json", dir.path())).is_err());
+    }
+}
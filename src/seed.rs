@@ -0,0 +1,95 @@
+// Synthetic offers for load testing, reproducible for a given seed
+use crate::models::{CarType, Offer};
+use crate::validation::OFFER_DATA_LEN;
+use uuid::Builder;
+
+const MILLIS_PER_DAY: i64 = 86_400_000;
+// 2024-01-01T00:00:00Z, rentals start on one of the following 365 days
+const FIRST_START_DATE: i64 = 1_704_067_200_000;
+
+// SplitMix64, tiny and with a fixed output sequence unlike the rand crate's StdRng
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [low, high], the modulo bias is irrelevant for these small ranges
+    fn range(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next_u64() % (high - low + 1)
+    }
+
+    fn fill(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            let random = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+}
+
+// Generates one random offer passing validate(), in regions 0..regions
+pub fn random_offer(rng: &mut Rng, regions: u32) -> Offer {
+    let mut id = [0u8; 16];
+    rng.fill(&mut id);
+    let mut data = vec![0u8; OFFER_DATA_LEN];
+    rng.fill(&mut data);
+
+    let start_date = FIRST_START_DATE + rng.range(0, 364) as i64 * MILLIS_PER_DAY;
+    let number_days = rng.range(1, 14) as i64;
+    let car_type = match rng.range(0, 3) {
+        0 => CarType::Small,
+        1 => CarType::Sports,
+        2 => CarType::Luxury,
+        _ => CarType::Family,
+    };
+
+    Offer {
+        ID: Builder::from_random_bytes(id).into_uuid(),
+        data,
+        mostSpecificRegionID: rng.range(0, regions.max(1) as u64 - 1) as i32,
+        startDate: start_date,
+        endDate: start_date + number_days * MILLIS_PER_DAY,
+        numberSeats: rng.range(2, 9) as u8,
+        price: rng.range(1, 5000) as u16,
+        carType: car_type,
+        hasVollkasko: rng.range(0, 1) == 1,
+        freeKilometers: rng.range(0, 5000) as u16,
+        createdAt: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::validate;
+
+    fn offers(seed: u64, count: usize) -> Vec<Offer> {
+        let mut rng = Rng::new(seed);
+        (0..count).map(|_| random_offer(&mut rng, 10)).collect()
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_offers() {
+        let json = |offers: Vec<Offer>| serde_json::to_value(offers).unwrap();
+        assert_eq!(json(offers(76, 100)), json(offers(76, 100)));
+        assert_ne!(json(offers(76, 100)), json(offers(77, 100)));
+    }
+
+    #[test]
+    fn seeded_offers_are_valid() {
+        for offer in offers(76, 1000) {
+            assert!(validate(&offer).is_ok(), "{:?}", offer);
+            assert!((0..10).contains(&offer.mostSpecificRegionID));
+            assert!(offer.startDate >= FIRST_START_DATE);
+        }
+    }
+}
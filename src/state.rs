@@ -0,0 +1,133 @@
+use crate::cache::{SharedCache, SharedFacetCache};
+use crate::codec::DataCompression;
+use crate::db::{Database, Shards, WriteRetry};
+use crate::regions::SharedRegions;
+use axum::extract::FromRef;
+use axum::http::HeaderMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// Serializes every write to RocksDB and the cache.
+//
+// A writer holds this lock for its whole read-modify-write against RocksDB, which can
+// take a while for big batches, and only takes the cache write lock at the end to apply
+// its changes in one go. Searches just take the cache read lock, so they are never
+// blocked by RocksDB I/O and always see the state before or after a write, never a mix.
+// Since writes are serialized the cache applies them in the same order as RocksDB.
+pub type WriteLock = Arc<Mutex<()>>;
+
+// Runs a write under the WriteLock on tokio's blocking pool, so RocksDB I/O and the retry
+// backoff don't stall the async workers. The write owns the lock and runs to completion,
+// cache update included, even when the request is dropped meanwhile, e.g. by the timeout.
+// It runs in the caller's span so its logs stay attached to the request.
+pub async fn run_write<T: Send + 'static>(write_lock: &WriteLock, write: impl FnOnce() -> T + Send + 'static) -> T {
+    let guard = write_lock.clone().lock_owned().await;
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _write = guard;
+        span.in_scope(write)
+    })
+    .await
+    .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+// Header carrying the admin token
+pub const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+// Token guarding the admin endpoints, without one configured every request is refused
+#[derive(Clone, Debug, Default)]
+pub struct AdminToken(pub Option<Arc<str>>);
+
+impl AdminToken {
+    pub fn accepts(&self, headers: &HeaderMap) -> bool {
+        let (expected, given) = match (&self.0, headers.get(ADMIN_TOKEN_HEADER)) {
+            (Some(expected), Some(given)) => (expected.as_bytes(), given.as_bytes()),
+            _ => return false,
+        };
+        // Compare every byte so the time taken doesn't tell how much of the token matched
+        expected.len() == given.len() && expected.iter().zip(given).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+// Request limits handlers enforce themselves, unlike the body limit layer
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_batch_ids: usize,
+    // Full scans over every region are opt-in
+    pub allow_global_query: bool,
+}
+
+// Shared application state, handlers extract the parts they need
+#[derive(Clone)]
+pub struct AppState {
+    pub db: Database,
+    pub cache: SharedCache,
+    pub regions: SharedRegions,
+    pub write_lock: WriteLock,
+    pub shards: Shards,
+    pub facets: SharedFacetCache,
+    pub write_retry: WriteRetry,
+    pub admin_token: AdminToken,
+    pub limits: Limits,
+    pub data_compression: DataCompression,
+}
+
+impl FromRef<AppState> for Database {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AppState> for SharedCache {
+    fn from_ref(state: &AppState) -> Self {
+        state.cache.clone()
+    }
+}
+
+impl FromRef<AppState> for SharedRegions {
+    fn from_ref(state: &AppState) -> Self {
+        state.regions.clone()
+    }
+}
+
+impl FromRef<AppState> for WriteLock {
+    fn from_ref(state: &AppState) -> Self {
+        state.write_lock.clone()
+    }
+}
+
+impl FromRef<AppState> for Shards {
+    fn from_ref(state: &AppState) -> Self {
+        state.shards
+    }
+}
+
+impl FromRef<AppState> for SharedFacetCache {
+    fn from_ref(state: &AppState) -> Self {
+        state.facets.clone()
+    }
+}
+
+impl FromRef<AppState> for WriteRetry {
+    fn from_ref(state: &AppState) -> Self {
+        state.write_retry
+    }
+}
+
+impl FromRef<AppState> for AdminToken {
+    fn from_ref(state: &AppState) -> Self {
+        state.admin_token.clone()
+    }
+}
+
+impl FromRef<AppState> for Limits {
+    fn from_ref(state: &AppState) -> Self {
+        state.limits
+    }
+}
+
+impl FromRef<AppState> for DataCompression {
+    fn from_ref(state: &AppState) -> Self {
+        state.data_compression
+    }
+}
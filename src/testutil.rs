@@ -0,0 +1,118 @@
+// Shared helpers of the unit tests
+use crate::cache::{FacetCache, OfferCache};
+use crate::config::Config;
+use crate::db::{init_db, Shards};
+use crate::models::{CarType, Offer};
+use crate::regions::RegionTree;
+use crate::state::{AdminToken, AppState, Limits};
+use crate::validation::OFFER_DATA_LEN;
+use axum::body::{Body, Bytes};
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+pub const DAY: i64 = 86_400_000;
+
+// A valid offer with a fresh ID, override fields with struct update syntax
+pub fn offer() -> Offer {
+    Offer {
+        ID: Uuid::new_v4(),
+        data: vec![7; OFFER_DATA_LEN],
+        mostSpecificRegionID: 1,
+        startDate: 10 * DAY,
+        endDate: 12 * DAY,
+        numberSeats: 4,
+        price: 100,
+        carType: CarType::Small,
+        hasVollkasko: false,
+        freeKilometers: 100,
+        createdAt: None,
+    }
+}
+
+// A database directory under the system temp dir, removed again on drop
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        TempDir(std::env::temp_dir().join(format!("hackatum24-test-{}", Uuid::new_v4())))
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Config from the given variables alone, ignoring the process environment
+pub fn test_config(vars: &[(&str, &str)]) -> Config {
+    let var = |key: &str| vars.iter().find(|(name, _)| *name == key).map(|(_, value)| value.to_string());
+    Config::from_vars(var).unwrap()
+}
+
+// State over a fresh database in dir set up like main does, with an empty region tree
+pub fn test_state_with(dir: &TempDir, config: &Config) -> AppState {
+    let shards = Shards::new(config.db.shards);
+    let db = init_db(dir.path(), &config.db).unwrap();
+    AppState {
+        cache: Arc::new(RwLock::new(OfferCache::load(&db, shards).unwrap())),
+        db,
+        regions: Arc::new(RegionTree::default()),
+        write_lock: Arc::new(tokio::sync::Mutex::new(())),
+        shards,
+        facets: Arc::new(FacetCache::default()),
+        write_retry: config.db.write_retry(),
+        admin_token: AdminToken(config.admin_token.as_deref().map(Arc::from)),
+        limits: Limits {
+            max_batch_ids: config.max_batch_ids,
+            allow_global_query: config.allow_global_query,
+        },
+        data_compression: config.data_compression,
+    }
+}
+
+pub fn test_state(dir: &TempDir) -> AppState {
+    test_state_with(dir, &test_config(&[]))
+}
+
+// The full router of main, configured by vars, and its state
+pub fn test_app(dir: &TempDir, vars: &[(&str, &str)]) -> (AppState, Router) {
+    let config = test_config(vars);
+    let state = test_state_with(dir, &config);
+    (state.clone(), crate::app(state, &config))
+}
+
+// Sends one request through the router
+pub async fn send(router: &Router, request: Request<Body>) -> Response {
+    router.clone().oneshot(request).await.unwrap()
+}
+
+pub fn request(method: &str, uri: &str) -> Request<Body> {
+    Request::builder().method(method).uri(uri).body(Body::empty()).unwrap()
+}
+
+pub fn json_request(method: &str, uri: &str, body: &serde_json::Value) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+pub async fn body_bytes(response: Response) -> Bytes {
+    hyper::body::to_bytes(response.into_body()).await.unwrap()
+}
+
+pub async fn body_json(response: Response) -> serde_json::Value {
+    serde_json::from_slice(&body_bytes(response).await).unwrap()
+}
@@ -0,0 +1,142 @@
+use crate::error::ApiError;
+use crate::models::{Offer, OfferValidationError, ValidationReport};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+// Size of the decoded data blob the dataset spec fixes for every offer
+pub const OFFER_DATA_LEN: usize = 256;
+
+// Checks the fields deserialization alone can't, reporting the first violation
+pub fn validate(offer: &Offer) -> Result<(), ApiError> {
+    if offer.startDate > offer.endDate {
+        return Err(invalid(offer, "startDate", "startDate is after endDate"));
+    }
+    if offer.numberSeats == 0 {
+        return Err(invalid(offer, "numberSeats", "numberSeats must be at least 1"));
+    }
+    if offer.price == 0 {
+        return Err(invalid(offer, "price", "price must be positive"));
+    }
+    if offer.data.len() != OFFER_DATA_LEN {
+        let message = format!("data must be {} bytes, got {}", OFFER_DATA_LEN, offer.data.len());
+        return Err(invalid(offer, "data", &message));
+    }
+    Ok(())
+}
+
+// Validates every offer instead of stopping at the first bad one.
+// Like check_unique_ids every repeat of an ID is an error, its first offer is checked as usual.
+pub fn validate_all(offers: &[Offer]) -> ValidationReport {
+    let mut report = ValidationReport {
+        valid: 0,
+        errors: Vec::new(),
+    };
+    let mut seen = HashSet::with_capacity(offers.len());
+    for offer in offers {
+        let result = match seen.insert(offer.ID) {
+            true => validate(offer),
+            false => Err(ApiError::bad_request("Duplicate offer ID").with_field("ID")),
+        };
+        match result {
+            Ok(()) => report.valid += 1,
+            Err(e) => report.errors.push(OfferValidationError {
+                ID: offer.ID,
+                error: e.body.error,
+                field: e.body.field,
+            }),
+        }
+    }
+    report
+}
+
+// POST /api/offers rejects a batch repeating an ID instead of quietly keeping one version
+pub fn check_unique_ids(offers: &[Offer]) -> Result<(), ApiError> {
+    let mut seen = HashSet::with_capacity(offers.len());
+    let mut duplicates = Vec::new();
+    for offer in offers {
+        if !seen.insert(offer.ID) && !duplicates.contains(&offer.ID) {
+            duplicates.push(offer.ID);
+        }
+    }
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<String> = duplicates.iter().map(|id| id.to_string()).collect();
+    Err(ApiError::bad_request(format!("Duplicate offer IDs: {}", ids.join(", "))).with_field("ID"))
+}
+
+// Keeps the last offer of every ID in batch order. Written together, both versions of an
+// ID would leave the index entries of the first one behind.
+pub fn dedup_ids(offers: &mut Vec<Offer>) {
+    let mut last: HashMap<Uuid, usize> = HashMap::with_capacity(offers.len());
+    for (index, offer) in offers.iter().enumerate() {
+        last.insert(offer.ID, index);
+    }
+    if last.len() == offers.len() {
+        return;
+    }
+    let mut index = 0;
+    offers.retain(|offer| {
+        let keep = last[&offer.ID] == index;
+        index += 1;
+        keep
+    });
+}
+
+fn invalid(offer: &Offer, field: &str, message: &str) -> ApiError {
+    ApiError::bad_request(format!("Invalid offer {}: {}", offer.ID, message)).with_field(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::offer;
+
+    fn rejected_field(offer: &Offer) -> Option<String> {
+        validate(offer).unwrap_err().body.field
+    }
+
+    #[test]
+    fn accepts_a_valid_offer() {
+        assert!(validate(&offer()).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_start_after_the_end() {
+        let offer = Offer {
+            startDate: 2,
+            endDate: 1,
+            ..offer()
+        };
+        assert_eq!(rejected_field(&offer).as_deref(), Some("startDate"));
+    }
+
+    #[test]
+    fn rejects_zero_seats() {
+        let offer = Offer {
+            numberSeats: 0,
+            ..offer()
+        };
+        assert_eq!(rejected_field(&offer).as_deref(), Some("numberSeats"));
+    }
+
+    #[test]
+    fn rejects_a_zero_price() {
+        let offer = Offer { price: 0, ..offer() };
+        assert_eq!(rejected_field(&offer).as_deref(), Some("price"));
+    }
+
+    #[test]
+    fn rejects_data_of_any_other_length_than_256_bytes() {
+        for len in [OFFER_DATA_LEN - 1, OFFER_DATA_LEN + 1] {
+            let offer = Offer {
+                data: vec![0; len],
+                ..offer()
+            };
+            let e = validate(&offer).unwrap_err();
+            assert_eq!(e.body.field.as_deref(), Some("data"));
+            assert!(e.body.error.contains(&format!("got {}", len)));
+        }
+        assert_eq!(OFFER_DATA_LEN, 256);
+    }
+}